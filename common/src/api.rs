@@ -1,8 +1,339 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, ffi::CString, vec};
 
-use reqwest::blocking::Client;
+use reqwest::{
+    StatusCode,
+    blocking::{Client, RequestBuilder, Response},
+};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
 
-use crate::{api::types::UserRepresentation, config::Config, token};
+use crate::{
+    api::types::{
+        ClientRepresentation, CredentialRepresentation, GroupRepresentation, RoleRepresentation,
+        UserRepresentation,
+    },
+    config::Config,
+    failover, token,
+};
+
+fn get<R, T, F>(
+    config: &Config,
+    path: &str,
+    query_parameters: HashMap<&str, T>,
+    debug_log: F,
+) -> Result<R, Box<dyn std::error::Error>>
+where
+    R: DeserializeOwned,
+    T: serde::Serialize + Sized,
+    F: FnOnce(String),
+{
+    let url = format!("{}/realms/{}/{path}", config.api_url, config.realm);
+    let res = send_authorised(config, &url, |client, url| {
+        client.get(url).query(&query_parameters)
+    })?
+    .error_for_status()?
+    .text()?;
+
+    if cfg!(debug_assertions) {
+        debug_log(res.clone());
+    }
+
+    Ok(serde_json::from_str(&res)?)
+}
+
+/// Get a listing, skipping any entries that can't be parsed.
+fn get_list<R, T, F>(
+    config: &Config,
+    path: &str,
+    query_parameters: HashMap<&str, T>,
+    debug_log: F,
+) -> Result<Vec<R>, Box<dyn std::error::Error>>
+where
+    R: DeserializeOwned,
+    T: serde::Serialize + Sized,
+    F: FnOnce(String),
+{
+    let entries = get(config, path, query_parameters, debug_log)?;
+    Ok(parse_entries(path, entries))
+}
+
+/// Parse each entry of a listing separately, so that one that can't be parsed
+/// is logged and skipped rather than failing the whole listing.
+fn parse_entries<R>(path: &str, entries: Vec<Value>) -> Vec<R>
+where
+    R: DeserializeOwned,
+{
+    entries
+        .into_iter()
+        .filter_map(|entry| match R::deserialize(&entry) {
+            Ok(parsed) => Some(parsed),
+            Err(e) => {
+                let name = ["username", "name", "id"]
+                    .iter()
+                    .find_map(|field| entry.get(field)?.as_str())
+                    .unwrap_or("unknown");
+                log_warning(&format!("Skipping {name:?} from {path}: {e}"));
+                None
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn log_warning(message: &str) {
+    let Ok(message) = CString::new(message) else {
+        return;
+    };
+    // SAFETY: both strings are NUL terminated, and the message is passed as
+    // an argument rather than a format
+    unsafe {
+        libc::syslog(libc::LOG_WARNING, c"%s".as_ptr(), message.as_ptr());
+    }
+}
+
+fn put<B, F>(
+    config: &Config,
+    path: &str,
+    body: &B,
+    debug_log: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    B: Serialize,
+    F: FnOnce(String),
+{
+    let body = serde_json::to_string(body)?;
+    if cfg!(debug_assertions) {
+        debug_log(body.clone());
+    }
+
+    let url = format!("{}/realms/{}/{path}", config.api_url, config.realm);
+    send_authorised(config, &url, |client, url| {
+        client
+            .put(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.clone())
+    })?
+    .error_for_status()?;
+
+    Ok(())
+}
+
+/// Send a request with the client's access token. If Keycloak rejects the
+/// token, it is forgotten and the request tried once more with a new one.
+fn send_authorised<F>(
+    config: &Config,
+    url: &str,
+    request: F,
+) -> Result<Response, Box<dyn std::error::Error>>
+where
+    F: Fn(&Client, &str) -> RequestBuilder,
+{
+    let send = |token: &str| {
+        failover::send(config, url, |client, url| {
+            request(client, url).bearer_auth(token)
+        })
+    };
+
+    let token = token::get_client_access_token(config).ok_or("failed to get token")?;
+    let res = send(&token)?;
+    if res.status() != StatusCode::UNAUTHORIZED {
+        return Ok(res);
+    }
+    token::forget_client_access_token(config, &token);
+
+    let token = token::get_client_access_token(config).ok_or("failed to get token")?;
+    Ok(send(&token)?)
+}
+
+/// Whether a request failed because what was asked for does not exist.
+pub fn is_not_found(error: &(dyn std::error::Error + 'static)) -> bool {
+    error
+        .downcast_ref::<reqwest::Error>()
+        .and_then(reqwest::Error::status)
+        == Some(StatusCode::NOT_FOUND)
+}
+
+/// Number of entries requested per page by [`Paged`].
+pub const PAGE_SIZE: usize = 100;
+
+/// An iterator over a listing from the admin API, which is fetched a page at
+/// a time using `first` and `max`.
+pub struct Paged<'a, R, F> {
+    config: &'a Config,
+    path: String,
+    query_parameters: HashMap<&'static str, String>,
+    debug_log: F,
+    first: usize,
+    page: vec::IntoIter<R>,
+    done: bool,
+}
+
+impl<'a, R, F> Paged<'a, R, F> {
+    fn new(
+        config: &'a Config,
+        path: String,
+        query_parameters: HashMap<&'static str, String>,
+        debug_log: F,
+    ) -> Self {
+        Self {
+            config,
+            path,
+            query_parameters,
+            debug_log,
+            first: 0,
+            page: vec![].into_iter(),
+            done: false,
+        }
+    }
+}
+
+impl<R, F> Iterator for Paged<'_, R, F>
+where
+    R: DeserializeOwned,
+    F: Fn(String),
+{
+    type Item = Result<R, Box<dyn std::error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.page.next() {
+                return Some(Ok(entry));
+            }
+            if self.done {
+                return None;
+            }
+
+            let mut query = self.query_parameters.clone();
+            query.insert("first", self.first.to_string());
+            query.insert("max", PAGE_SIZE.to_string());
+            match get::<Vec<Value>, _, _>(self.config, &self.path, query, |v| (self.debug_log)(v)) {
+                Ok(page) => {
+                    // Entries that fail to parse still count towards the page
+                    self.done = page.len() < PAGE_SIZE;
+                    self.first += page.len();
+                    self.page = parse_entries(&self.path, page).into_iter();
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Iterate over every user in the realm. Brief representations omit
+/// attributes.
+pub fn users<F>(
+    config: &Config,
+    brief_representation: bool,
+    debug_log: F,
+) -> Paged<'_, UserRepresentation, F>
+where
+    F: Fn(String),
+{
+    let mut query = HashMap::new();
+    query.insert("briefRepresentation", brief_representation.to_string());
+    Paged::new(config, "users".to_string(), query, debug_log)
+}
+
+/// Iterate over the top level groups in the realm, including their
+/// attributes.
+pub fn groups<F>(config: &Config, debug_log: F) -> Paged<'_, GroupRepresentation, F>
+where
+    F: Fn(String),
+{
+    let mut query = HashMap::new();
+    query.insert("briefRepresentation", "false".to_string());
+    Paged::new(config, "groups".to_string(), query, debug_log)
+}
+
+/// Iterate over the groups matching a search, such as `search` for a name or
+/// `q` for attributes. Matching subgroups are returned within their top
+/// level groups.
+pub fn search_groups<'a, F>(
+    config: &'a Config,
+    mut query_parameters: HashMap<&'static str, String>,
+    debug_log: F,
+) -> Paged<'a, GroupRepresentation, F>
+where
+    F: Fn(String),
+{
+    query_parameters.insert("briefRepresentation", "false".to_string());
+    Paged::new(config, "groups".to_string(), query_parameters, debug_log)
+}
+
+/// Iterate over the direct subgroups of a group, including their attributes.
+/// Newer Keycloak versions no longer include these in [`groups`].
+pub fn group_children<'a, F>(
+    config: &'a Config,
+    group_id: &str,
+    debug_log: F,
+) -> Paged<'a, GroupRepresentation, F>
+where
+    F: Fn(String),
+{
+    let mut query = HashMap::new();
+    query.insert("briefRepresentation", "false".to_string());
+    Paged::new(
+        config,
+        format!("groups/{group_id}/children"),
+        query,
+        debug_log,
+    )
+}
+
+/// Iterate over the members of a group, including their attributes.
+pub fn group_members<'a, F>(
+    config: &'a Config,
+    group_id: &str,
+    debug_log: F,
+) -> Paged<'a, UserRepresentation, F>
+where
+    F: Fn(String),
+{
+    let mut query = HashMap::new();
+    query.insert("briefRepresentation", "false".to_string());
+    Paged::new(
+        config,
+        format!("groups/{group_id}/members"),
+        query,
+        debug_log,
+    )
+}
+
+/// Iterate over the groups a user is a member of, including their attributes.
+pub fn user_groups<'a, F>(
+    config: &'a Config,
+    user_id: &str,
+    debug_log: F,
+) -> Paged<'a, GroupRepresentation, F>
+where
+    F: Fn(String),
+{
+    let mut query = HashMap::new();
+    query.insert("briefRepresentation", "false".to_string());
+    Paged::new(config, format!("users/{user_id}/groups"), query, debug_log)
+}
+
+/// Iterate over the users given a role directly, not through a composite role
+/// or group. `client_id` is the internal ID of the client for client roles.
+pub fn role_users<'a, F>(
+    config: &'a Config,
+    client_id: Option<&str>,
+    role: &str,
+    debug_log: F,
+) -> Paged<'a, UserRepresentation, F>
+where
+    F: Fn(String),
+{
+    let path = match client_id {
+        Some(client_id) => format!("clients/{client_id}/roles/{role}/users"),
+        None => format!("roles/{role}/users"),
+    };
+    let mut query = HashMap::new();
+    query.insert("briefRepresentation", "false".to_string());
+    Paged::new(config, path, query, debug_log)
+}
 
 pub fn get_users<T, F>(
     config: &Config,
@@ -13,28 +344,162 @@ where
     T: serde::Serialize + Sized,
     F: FnOnce(String),
 {
-    let token =
-        token::get_client_access_token(&config.token_url, &config.client_id, &config.client_secret)
-            .ok_or("")?;
+    get_list(config, "users", query_parameters, debug_log)
+}
 
-    let client = Client::new();
+pub fn get_user<F>(
+    config: &Config,
+    user_id: &str,
+    debug_log: F,
+) -> Result<UserRepresentation, Box<dyn std::error::Error>>
+where
+    F: FnOnce(String),
+{
+    get(
+        config,
+        &format!("users/{user_id}"),
+        HashMap::<&str, &str>::new(),
+        debug_log,
+    )
+}
 
-    if cfg!(debug_assertions) {
-        let res = client
-            .get(format!("{}/realms/{}/users", config.api_url, config.realm))
-            .bearer_auth(&token)
-            .query(&query_parameters)
-            .send()?;
-        debug_log(res.text().unwrap());
+/// Set one attribute of a user, leaving everything else as it currently is
+/// in Keycloak.
+///
+/// The user is fetched again rather than written back from an older copy, and
+/// only the fields needed to pass user profile validation are sent with the
+/// attributes, so concurrent changes to the user aren't overwritten.
+pub fn set_user_attribute<F>(
+    config: &Config,
+    user_id: &str,
+    attribute: &str,
+    values: Vec<String>,
+    debug_log: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: Fn(String),
+{
+    let path = format!("users/{user_id}");
+    let current: Value = get(config, &path, HashMap::<&str, &str>::new(), &debug_log)?;
+
+    let mut update = Map::new();
+    for field in ["username", "email", "firstName", "lastName"] {
+        if let Some(value) = current.get(field) {
+            update.insert(field.to_string(), value.clone());
+        }
     }
+    let mut attributes = current
+        .get("attributes")
+        .and_then(Value::as_object)
+        .cloned()
+        .unwrap_or_default();
+    attributes.insert(attribute.to_string(), values.into());
+    update.insert("attributes".to_string(), attributes.into());
+
+    put(config, &path, &update, debug_log)
+}
+
+pub fn get_user_credentials<F>(
+    config: &Config,
+    user_id: &str,
+    debug_log: F,
+) -> Result<Vec<CredentialRepresentation>, Box<dyn std::error::Error>>
+where
+    F: FnOnce(String),
+{
+    get_list(
+        config,
+        &format!("users/{user_id}/credentials"),
+        HashMap::<&str, &str>::new(),
+        debug_log,
+    )
+}
+
+/// Get the effective realm roles of a user, including those from composite
+/// roles and group membership.
+pub fn get_user_realm_roles<T, F>(
+    config: &Config,
+    user_id: &str,
+    query_parameters: HashMap<&str, T>,
+    debug_log: F,
+) -> Result<Vec<RoleRepresentation>, Box<dyn std::error::Error>>
+where
+    T: serde::Serialize + Sized,
+    F: FnOnce(String),
+{
+    get_list(
+        config,
+        &format!("users/{user_id}/role-mappings/realm/composite"),
+        query_parameters,
+        debug_log,
+    )
+}
+
+/// Get the effective roles of a user for a client, including those from
+/// composite roles and group membership. `client_id` is the client's internal
+/// ID, not its client ID.
+pub fn get_user_client_roles<T, F>(
+    config: &Config,
+    user_id: &str,
+    client_id: &str,
+    query_parameters: HashMap<&str, T>,
+    debug_log: F,
+) -> Result<Vec<RoleRepresentation>, Box<dyn std::error::Error>>
+where
+    T: serde::Serialize + Sized,
+    F: FnOnce(String),
+{
+    get_list(
+        config,
+        &format!("users/{user_id}/role-mappings/clients/{client_id}/composite"),
+        query_parameters,
+        debug_log,
+    )
+}
 
-    let res = client
-        .get(format!("{}/realms/{}/users", config.api_url, config.realm))
-        .bearer_auth(token)
-        .query(&query_parameters)
-        .send()?;
+/// Get a group by its path, such as `/parent/child`.
+pub fn get_group_by_path<F>(
+    config: &Config,
+    path: &str,
+    debug_log: F,
+) -> Result<GroupRepresentation, Box<dyn std::error::Error>>
+where
+    F: FnOnce(String),
+{
+    get(
+        config,
+        &format!("group-by-path/{}", path.trim_start_matches('/')),
+        HashMap::<&str, &str>::new(),
+        debug_log,
+    )
+}
 
-    Ok(res.json::<Vec<UserRepresentation>>()?)
+/// Find a client by its client ID, rather than its internal ID.
+pub fn find_client<F>(
+    config: &Config,
+    client_id: &str,
+    debug_log: F,
+) -> Result<Option<ClientRepresentation>, Box<dyn std::error::Error>>
+where
+    F: FnOnce(String),
+{
+    let mut query = HashMap::new();
+    query.insert("clientId", client_id);
+    Ok(get_clients(config, query, debug_log)?
+        .into_iter()
+        .find(|c| c.client_id == client_id))
+}
+
+pub fn get_clients<T, F>(
+    config: &Config,
+    query_parameters: HashMap<&str, T>,
+    debug_log: F,
+) -> Result<Vec<ClientRepresentation>, Box<dyn std::error::Error>>
+where
+    T: serde::Serialize + Sized,
+    F: FnOnce(String),
+{
+    get_list(config, "clients", query_parameters, debug_log)
 }
 
 pub mod types {
@@ -47,11 +512,186 @@ pub mod types {
     pub struct UserRepresentation {
         pub id: String,
         pub username: String,
-        pub first_name: String,
-        pub last_name: String,
+        #[serde(default)]
+        pub first_name: Option<String>,
+        #[serde(default)]
+        pub last_name: Option<String>,
+        #[serde(default)]
+        pub email: Option<String>,
+        #[serde(default)]
+        pub attributes: HashMap<String, Vec<String>>,
+        #[serde(default = "default_enabled")]
+        pub enabled: bool,
+        #[serde(default)]
+        pub email_verified: bool,
+        /// Actions the user must take before they can log in, such as
+        /// `UPDATE_PASSWORD`.
+        #[serde(default)]
+        pub required_actions: Vec<String>,
+        /// Milliseconds since the Unix epoch at which the user was created.
+        #[serde(default)]
+        pub created_timestamp: Option<i64>,
+
+        #[serde(flatten)]
+        pub _the_rest: HashMap<String, serde_json::Value>,
+    }
+
+    impl UserRepresentation {
+        /// Get the first value of an attribute, if it is set and not empty.
+        pub fn attribute(&self, id: &str) -> Option<&str> {
+            self.attributes
+                .get(id)?
+                .first()
+                .map(String::as_str)
+                .filter(|v| !v.is_empty())
+        }
+    }
+
+    fn default_enabled() -> bool {
+        true
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct CredentialRepresentation {
+        pub id: String,
+        #[serde(rename = "type")]
+        pub credential_type: String,
+        /// Milliseconds since the Unix epoch.
+        pub created_date: Option<i64>,
+
+        #[serde(flatten)]
+        pub _the_rest: HashMap<String, serde_json::Value>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct GroupRepresentation {
+        pub id: String,
+        pub name: String,
+        pub path: String,
+        #[serde(default)]
         pub attributes: HashMap<String, Vec<String>>,
+        #[serde(default)]
+        pub sub_group_count: Option<u64>,
+        #[serde(default)]
+        pub sub_groups: Vec<GroupRepresentation>,
+
+        #[serde(flatten)]
+        pub _the_rest: HashMap<String, serde_json::Value>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct RoleRepresentation {
+        pub id: String,
+        pub name: String,
+
+        #[serde(flatten)]
+        pub _the_rest: HashMap<String, serde_json::Value>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ClientRepresentation {
+        pub id: String,
+        pub client_id: String,
 
         #[serde(flatten)]
         pub _the_rest: HashMap<String, serde_json::Value>,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::{
+        parse_entries,
+        types::{CredentialRepresentation, GroupRepresentation, UserRepresentation},
+    };
+
+    fn parse<R: serde::de::DeserializeOwned>(json: &str) -> Vec<R> {
+        let entries: Vec<Value> = serde_json::from_str(json).unwrap();
+        parse_entries("test", entries)
+    }
+
+    #[test]
+    fn parses_keycloak_21_users() {
+        let users: Vec<UserRepresentation> =
+            parse(include_str!("../testdata/keycloak-21-users.json"));
+        assert_eq!(users.len(), 2);
+
+        let alice = &users[0];
+        assert_eq!(alice.username, "alice");
+        assert_eq!(alice.first_name.as_deref(), Some("Alice"));
+        assert_eq!(alice.last_name.as_deref(), Some("Smith"));
+        assert_eq!(alice.email.as_deref(), Some("alice@example.com"));
+        assert_eq!(alice.attribute("linux_uid"), Some("1000"));
+        assert!(alice.enabled && alice.email_verified);
+
+        // Service accounts have no names or attributes
+        let service_account = &users[1];
+        assert_eq!(service_account.first_name, None);
+        assert_eq!(service_account.last_name, None);
+        assert!(service_account.attributes.is_empty());
+    }
+
+    #[test]
+    fn parses_keycloak_26_users() {
+        let users: Vec<UserRepresentation> =
+            parse(include_str!("../testdata/keycloak-26-users.json"));
+        assert_eq!(users.len(), 2);
+
+        let bob = &users[0];
+        assert_eq!(bob.first_name.as_deref(), Some("Bob"));
+        assert_eq!(bob.last_name, None);
+        assert_eq!(bob.attribute("loginShell"), Some("/bin/zsh"));
+        assert!(!bob.enabled && !bob.email_verified);
+        assert_eq!(bob.required_actions, ["UPDATE_PASSWORD", "VERIFY_EMAIL"]);
+
+        let carol = &users[1];
+        assert_eq!(carol.attribute("linux_uid"), None);
+        assert!(carol.required_actions.is_empty());
+    }
+
+    #[test]
+    fn skips_invalid_users() {
+        let users: Vec<UserRepresentation> = parse(include_str!("../testdata/invalid-users.json"));
+        let usernames = users
+            .iter()
+            .map(|u| u.username.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(usernames, ["alice", "dave"]);
+    }
+
+    #[test]
+    fn parses_nested_keycloak_21_groups() {
+        let groups: Vec<GroupRepresentation> =
+            parse(include_str!("../testdata/keycloak-21-groups.json"));
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].sub_group_count, None);
+        assert_eq!(groups[0].sub_groups.len(), 1);
+        assert_eq!(groups[0].sub_groups[0].path, "/unix/admins");
+    }
+
+    #[test]
+    fn parses_counted_keycloak_26_groups() {
+        let groups: Vec<GroupRepresentation> =
+            parse(include_str!("../testdata/keycloak-26-groups.json"));
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].sub_group_count, Some(1));
+        assert!(groups[0].sub_groups.is_empty());
+        assert_eq!(groups[0].attributes["linux_gid"], ["2000"]);
+    }
+
+    #[test]
+    fn parses_keycloak_26_credentials() {
+        let credentials: Vec<CredentialRepresentation> =
+            parse(include_str!("../testdata/keycloak-26-credentials.json"));
+        assert_eq!(credentials.len(), 2);
+        assert_eq!(credentials[0].credential_type, "password");
+        assert_eq!(credentials[0].created_date, Some(1735689600123));
+        assert_eq!(credentials[1].credential_type, "otp");
+    }
+}
@@ -1,44 +1,380 @@
-use std::{fs, io, os::unix::fs::PermissionsExt, path::PathBuf};
+use std::{collections::HashMap, fs, io, os::unix::fs::PermissionsExt, path::PathBuf};
 
 use serde::{Deserialize, Serialize};
 
+use crate::discovery::{self, Metadata};
+
 pub const CONFIG_PATH: &str = "/etc/auth_keycloak.toml";
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
 pub struct Config {
+    /// The realm's issuer URL, such as `https://example.com/realms/master`.
+    /// If set, the realm and the URLs below are found from it by OpenID
+    /// Connect discovery.
+    pub issuer_url: Option<String>,
     pub token_url: String,
     pub userinfo_url: String,
     pub api_url: String,
+    /// Base URLs of further Keycloak nodes, such as `https://kc2.example.com`,
+    /// tried in order when the one in the URLs above can't be reached. Each
+    /// replaces the scheme, host and port of those URLs.
+    pub failover_urls: Vec<String>,
     pub realm: String,
     pub uid_attribute_id: String,
     pub uid_token_claim: String,
+    pub gid_attribute_id: String,
+    /// Supplementary groups granted by Keycloak roles, keyed by role. Realm
+    /// roles are given by name, client roles as `client_id/role`.
+    pub role_groups: HashMap<String, libc::gid_t>,
+    /// User attribute holding the date the account expires, either as
+    /// `YYYY-MM-DD` or as days since 1970-01-01.
+    pub account_expiry_attribute_id: String,
+    /// User attribute holding the maximum number of days between password
+    /// changes.
+    pub password_max_days_attribute_id: String,
+    /// How users disabled in Keycloak are shown.
+    pub disabled_users: DisabledUsers,
     pub client_id: String,
     pub client_secret: String,
     pub scopes: String,
     pub start_uid: libc::uid_t,
+    /// The last UID given to users without one. Allocation fails once every
+    /// UID up to this is taken.
+    pub end_uid: libc::uid_t,
+    /// The lowest UID a Keycloak user may have. Users with lower UIDs are
+    /// hidden and refused.
+    pub min_uid: libc::uid_t,
+    /// The highest UID a Keycloak user may have.
+    pub max_uid: libc::uid_t,
+    /// The lowest GID a Keycloak user's GID attribute or a Keycloak group may
+    /// have. Users and groups with lower GIDs are hidden, so that they can't
+    /// be given system groups.
+    pub min_gid: libc::gid_t,
+    /// The highest GID a Keycloak user's GID attribute or a Keycloak group
+    /// may have.
+    pub max_gid: libc::gid_t,
+    /// When users without a UID attribute are given one.
+    pub uid_assignment: UidAssignment,
     pub group_id: libc::uid_t,
     pub home_directory_parent: PathBuf,
     pub shell: String,
+    /// Template for the GECOS field, see [`crate::user::render`].
+    pub gecos_template: String,
+    /// Template for home directories, see [`crate::user::render`]. If unset,
+    /// homes are named after the user under `home_directory_parent`.
+    pub home_template: Option<String>,
+    /// User attribute holding the GID of their primary group, used instead of
+    /// `group_id` when set.
+    pub primary_gid_attribute_id: String,
+    /// User attribute holding their home directory, used instead of one under
+    /// `home_directory_parent` when set. It must be below that directory.
+    pub home_attribute_id: String,
+    /// User attribute holding their login shell, used instead of `shell` when
+    /// set. It must be listed in `/etc/shells`.
+    pub shell_attribute_id: String,
+    /// Only show and let log in the members of these Keycloak groups, given
+    /// by path, and of their subgroups. If neither this nor `allowed_roles`
+    /// is set, every user is.
+    pub allowed_groups: Vec<String>,
+    /// Only show and let log in the users with these roles. Realm roles are
+    /// given by name, client roles as `client_id/role`.
+    pub allowed_roles: Vec<String>,
+    /// Only let users log in to this host if their attribute or client roles
+    /// allow it.
+    pub host_access: Option<HostAccess>,
+    /// Answer NSS lookups through `nss-keycloakd` rather than contacting
+    /// Keycloak from every process.
+    pub daemon: bool,
+    /// Seconds for which users found in Keycloak are answered from the cache.
+    pub cache_ttl: u64,
+    /// Seconds for which users not found in Keycloak are remembered as
+    /// missing.
+    pub negative_cache_ttl: u64,
+    /// Share client access tokens between processes through a file only
+    /// readable by root, rather than each process fetching its own.
+    pub share_token: bool,
+    /// Derive UIDs and GIDs from Keycloak IDs rather than storing them in
+    /// attributes.
+    pub id_mapping: Option<IdMapping>,
+    /// Show users as `username@realm`, so that users of different realms
+    /// can have the same username.
+    pub qualify_names: bool,
+    /// Further realms searched in order after `realm`.
+    pub realms: Vec<RealmConfig>,
+    /// The endpoints found from `issuer_url`.
+    #[serde(skip)]
+    pub provider: Option<Metadata>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            issuer_url: None,
             token_url: "https://example.com/realms/master/protocol/openid-connect/token"
                 .to_string(),
             userinfo_url: "https://example.com/realms/master/protocol/openid-connect/userinfo"
                 .to_string(),
             api_url: "https://example.com/admin".to_string(),
+            failover_urls: vec![],
             realm: "master".to_string(),
             uid_attribute_id: "linux_uid".to_string(),
             uid_token_claim: "uid".to_string(),
+            gid_attribute_id: "linux_gid".to_string(),
+            role_groups: HashMap::new(),
+            account_expiry_attribute_id: "account_expiry".to_string(),
+            password_max_days_attribute_id: "password_max_days".to_string(),
+            disabled_users: DisabledUsers::default(),
             client_id: String::default(),
             client_secret: String::default(),
             scopes: "openid profile email uid".to_string(),
             start_uid: 1000,
+            end_uid: 59999,
+            min_uid: 1000,
+            max_uid: libc::uid_t::MAX - 1,
+            min_gid: 1000,
+            max_gid: libc::gid_t::MAX - 1,
+            uid_assignment: UidAssignment::default(),
             group_id: 1000,
             home_directory_parent: PathBuf::from("/home"),
             shell: "/bin/bash".to_string(),
+            gecos_template: "%f %l".to_string(),
+            home_template: None,
+            primary_gid_attribute_id: "gidNumber".to_string(),
+            home_attribute_id: "homeDirectory".to_string(),
+            shell_attribute_id: "loginShell".to_string(),
+            allowed_groups: vec![],
+            allowed_roles: vec![],
+            host_access: None,
+            daemon: false,
+            cache_ttl: 300,
+            negative_cache_ttl: 60,
+            share_token: false,
+            id_mapping: None,
+            qualify_names: false,
+            realms: vec![],
+            provider: None,
+        }
+    }
+}
+
+/// A further realm to search. Anything not set is the same as for the main
+/// realm, with the URLs changed to point at this realm.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RealmConfig {
+    pub realm: String,
+    pub token_url: Option<String>,
+    pub userinfo_url: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub uid_attribute_id: Option<String>,
+    pub uid_token_claim: Option<String>,
+    pub start_uid: Option<libc::uid_t>,
+    pub end_uid: Option<libc::uid_t>,
+    pub min_uid: Option<libc::uid_t>,
+    pub max_uid: Option<libc::uid_t>,
+    pub min_gid: Option<libc::gid_t>,
+    pub max_gid: Option<libc::gid_t>,
+}
+
+/// How users disabled in Keycloak are shown.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DisabledUsers {
+    /// Not at all, as if they didn't exist.
+    #[default]
+    Hide,
+    /// As usual, but with their account expired.
+    Lock,
+}
+
+/// When users without a UID attribute are given one. Until then, they are
+/// invisible to NSS.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UidAssignment {
+    /// When they are first looked up through NSS.
+    #[default]
+    Lookup,
+    /// When they first authenticate through PAM.
+    Login,
+    /// Never, leaving UIDs to be set in Keycloak.
+    Never,
+}
+
+/// Which hosts users may log in to, given as patterns naming hosts or host
+/// groups, where `*` matches anything and `?` any one character.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct HostAccess {
+    /// User attribute listing the patterns, as separate values or separated
+    /// by commas.
+    pub attribute_id: String,
+    /// Client whose roles a user has, named after patterns, also let them
+    /// log in.
+    pub client_id: Option<String>,
+    /// The name of this host, if not its hostname.
+    pub hostname: Option<String>,
+    /// The groups this host is in.
+    pub host_groups: Vec<String>,
+    /// Also hide users from NSS on hosts they may not log in to.
+    pub nss: bool,
+}
+
+impl Default for HostAccess {
+    fn default() -> Self {
+        Self {
+            attribute_id: "login_hosts".to_string(),
+            client_id: None,
+            hostname: None,
+            host_groups: vec![],
+            nss: false,
+        }
+    }
+}
+
+/// A range into which Keycloak user and group IDs are mapped, so that every
+/// host derives the same UIDs and GIDs without storing them in Keycloak.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct IdMapping {
+    pub min_id: u32,
+    pub max_id: u32,
+}
+
+impl Default for IdMapping {
+    fn default() -> Self {
+        Self {
+            min_id: 200_000,
+            max_id: 2_000_200_000,
+        }
+    }
+}
+
+impl IdMapping {
+    /// Map a Keycloak ID into the range.
+    pub fn map_id(&self, id: &str) -> u32 {
+        // 64-bit FNV-1a, which is stable between builds and hosts
+        let hash = id
+            .to_ascii_lowercase()
+            .bytes()
+            .fold(0xcbf29ce484222325u64, |hash, b| {
+                (hash ^ b as u64).wrapping_mul(0x100000001b3)
+            });
+        let size = self.max_id.saturating_sub(self.min_id) as u64 + 1;
+        self.min_id + (hash % size) as u32
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        (self.min_id..=self.max_id).contains(&id)
+    }
+}
+
+impl Config {
+    /// Whether a Keycloak user may have a UID.
+    pub fn allows_uid(&self, uid: libc::uid_t) -> bool {
+        (self.min_uid..=self.max_uid).contains(&uid)
+    }
+
+    /// Whether a user's GID attribute or a Keycloak group may have a GID.
+    pub fn allows_gid(&self, gid: libc::gid_t) -> bool {
+        (self.min_gid..=self.max_gid).contains(&gid)
+    }
+
+    /// Get the config for each realm, in the order they are searched.
+    pub fn realm_configs(&self) -> Vec<Config> {
+        let main = Config {
+            realms: vec![],
+            ..self.clone()
+        };
+        let mut configs = vec![main.clone()];
+        for realm in &self.realms {
+            let realm_url = |url: &str| {
+                url.replace(
+                    &format!("/realms/{}/", main.realm),
+                    &format!("/realms/{}/", realm.realm),
+                )
+            };
+            let main = main.clone();
+            configs.push(Config {
+                realm: realm.realm.clone(),
+                token_url: realm
+                    .token_url
+                    .clone()
+                    .unwrap_or_else(|| realm_url(&main.token_url)),
+                userinfo_url: realm
+                    .userinfo_url
+                    .clone()
+                    .unwrap_or_else(|| realm_url(&main.userinfo_url)),
+                client_id: realm.client_id.clone().unwrap_or(main.client_id),
+                client_secret: realm.client_secret.clone().unwrap_or(main.client_secret),
+                uid_attribute_id: realm
+                    .uid_attribute_id
+                    .clone()
+                    .unwrap_or(main.uid_attribute_id),
+                uid_token_claim: realm
+                    .uid_token_claim
+                    .clone()
+                    .unwrap_or(main.uid_token_claim),
+                start_uid: realm.start_uid.unwrap_or(main.start_uid),
+                end_uid: realm.end_uid.unwrap_or(main.end_uid),
+                min_uid: realm.min_uid.unwrap_or(main.min_uid),
+                max_uid: realm.max_uid.unwrap_or(main.max_uid),
+                min_gid: realm.min_gid.unwrap_or(main.min_gid),
+                max_gid: realm.max_gid.unwrap_or(main.max_gid),
+                ..main
+            });
+        }
+        configs
+    }
+
+    /// Check that no two realms can give out the same UID or GID, as each
+    /// realm only checks its own users and groups for duplicates.
+    fn check_id_ranges(&self) -> Result<(), io::Error> {
+        if self.id_mapping.is_some() && !self.realms.is_empty() {
+            // Every realm would map IDs into the same range
+            return Err(io::Error::other(
+                "id_mapping can't be used with further realms",
+            ));
+        }
+        let overlap = |a: (u32, u32), b: (u32, u32)| a.0 <= b.1 && b.0 <= a.1;
+        let configs = self.realm_configs();
+        for (i, a) in configs.iter().enumerate() {
+            for b in &configs[i + 1..] {
+                if overlap((a.min_uid, a.max_uid), (b.min_uid, b.max_uid)) {
+                    return Err(io::Error::other(format!(
+                        "UID ranges of realms {} and {} overlap, set min_uid and max_uid apart",
+                        a.realm, b.realm
+                    )));
+                }
+                if overlap((a.min_gid, a.max_gid), (b.min_gid, b.max_gid)) {
+                    return Err(io::Error::other(format!(
+                        "GID ranges of realms {} and {} overlap, set min_gid and max_gid apart",
+                        a.realm, b.realm
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The name a Keycloak user is shown by.
+    pub fn unix_name(&self, username: &str) -> String {
+        if self.qualify_names {
+            format!("{username}@{}", self.realm)
+        } else {
+            username.to_string()
+        }
+    }
+
+    /// The Keycloak username of a name from [`Config::unix_name`], if it is
+    /// one of this realm.
+    pub fn keycloak_name<'a>(&self, name: &'a str) -> Option<&'a str> {
+        if self.qualify_names {
+            name.strip_suffix(&format!("@{}", self.realm))
+        } else {
+            Some(name)
         }
     }
 }
@@ -55,8 +391,100 @@ pub fn create_if_not_exists() -> Result<(), io::Error> {
     Ok(())
 }
 
+/// Read the config file as it is, without contacting Keycloak. If it gives
+/// an issuer URL, the realm and URLs are only known after [`load`].
 pub fn read() -> Result<Config, io::Error> {
     fs::set_permissions(CONFIG_PATH, fs::Permissions::from_mode(0o0600))?;
-    toml::from_str(&fs::read_to_string(CONFIG_PATH)?)
-        .map_err(|_| io::Error::other("failed to deserialize"))
+    let config: Config = toml::from_str(&fs::read_to_string(CONFIG_PATH)?)
+        .map_err(|_| io::Error::other("failed to deserialize"))?;
+    config.check_id_ranges()?;
+    Ok(config)
+}
+
+/// Read the config file, and find the realm and URLs by OpenID Connect
+/// discovery if it gives an issuer URL.
+pub fn load() -> Result<Config, io::Error> {
+    let mut config = read()?;
+    discovery::discover(&mut config)?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, IdMapping, RealmConfig};
+
+    /// A realm with the same UID and GID range.
+    fn realm(name: &str, min_id: u32, max_id: u32) -> RealmConfig {
+        RealmConfig {
+            realm: name.to_string(),
+            min_uid: Some(min_id),
+            max_uid: Some(max_id),
+            min_gid: Some(min_id),
+            max_gid: Some(max_id),
+            ..Default::default()
+        }
+    }
+
+    fn config(realms: Vec<RealmConfig>) -> Config {
+        Config {
+            max_uid: 69999,
+            max_gid: 69999,
+            realms,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn accepts_separate_id_ranges() {
+        let config = config(vec![realm("a", 70000, 79999), realm("b", 80000, 89999)]);
+        assert!(config.check_id_ranges().is_ok());
+    }
+
+    #[test]
+    fn rejects_inherited_id_ranges() {
+        let config = Config {
+            realms: vec![RealmConfig {
+                realm: "a".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(config.check_id_ranges().is_err());
+    }
+
+    #[test]
+    fn rejects_overlapping_uid_ranges() {
+        let config = config(vec![
+            realm("a", 70000, 79999),
+            RealmConfig {
+                min_uid: Some(79999),
+                ..realm("b", 80000, 89999)
+            },
+        ]);
+        assert!(config.check_id_ranges().is_err());
+    }
+
+    #[test]
+    fn rejects_overlapping_gid_ranges() {
+        let config = config(vec![
+            realm("a", 70000, 79999),
+            RealmConfig {
+                min_gid: Some(1000),
+                ..realm("b", 80000, 89999)
+            },
+        ]);
+        assert!(config.check_id_ranges().is_err());
+    }
+
+    #[test]
+    fn rejects_id_mapping_with_realms() {
+        let config = Config {
+            id_mapping: Some(IdMapping {
+                min_id: 100000,
+                max_id: 199999,
+            }),
+            ..config(vec![realm("a", 70000, 79999)])
+        };
+        assert!(config.check_id_ranges().is_err());
+    }
 }
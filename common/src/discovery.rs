@@ -0,0 +1,116 @@
+//! OpenID Connect discovery, so that only the issuer URL needs configuring.
+//! The provider's metadata is kept on disk, so that it is still known when
+//! Keycloak can't be reached.
+
+use std::{fs, io};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    failover,
+    file::{self, now},
+};
+
+/// Where the provider's metadata is kept. It is public, so readable by all.
+pub const METADATA_CACHE_PATH: &str = "/var/cache/auth_keycloak_oidc.json";
+
+/// Seconds for which the kept metadata is used without fetching it again.
+const METADATA_TTL: u64 = 24 * 60 * 60;
+
+/// The endpoints of an OpenID provider, from
+/// `.well-known/openid-configuration`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Metadata {
+    pub issuer: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub jwks_uri: String,
+    pub introspection_endpoint: Option<String>,
+    pub end_session_endpoint: Option<String>,
+    pub device_authorization_endpoint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedMetadata {
+    /// The configured issuer URL the metadata was fetched for.
+    issuer_url: String,
+    /// Seconds since the Unix epoch at which the metadata was fetched.
+    fetched_at: u64,
+    metadata: Metadata,
+}
+
+/// Fill in the realm and the endpoint URLs of a config from its issuer.
+pub fn discover(config: &mut Config) -> io::Result<()> {
+    let Some(issuer) = config.issuer_url.as_deref() else {
+        return Ok(());
+    };
+    let issuer = issuer.trim_end_matches('/').to_string();
+    let (base, realm) = issuer
+        .rsplit_once("/realms/")
+        .ok_or_else(|| io::Error::other("issuer URL is not a Keycloak realm"))?;
+    config.api_url = format!("{base}/admin");
+    config.realm = realm.to_string();
+
+    let metadata = metadata(config, &issuer, realm)?;
+    config.token_url = metadata.token_endpoint.clone();
+    config.userinfo_url = metadata.userinfo_endpoint.clone();
+    config.provider = Some(metadata);
+    Ok(())
+}
+
+/// Get the metadata of an issuer, from disk if fetched recently enough, or
+/// else from Keycloak, falling back to the copy on disk however old it is.
+fn metadata(config: &Config, issuer: &str, realm: &str) -> io::Result<Metadata> {
+    let cached = read_cached(issuer);
+    if let Some(cached) = cached
+        .as_ref()
+        .filter(|c| now().saturating_sub(c.fetched_at) < METADATA_TTL)
+    {
+        return Ok(cached.metadata.clone());
+    }
+
+    match fetch(config, issuer, realm) {
+        Ok(metadata) => {
+            let _ = write_cached(&CachedMetadata {
+                issuer_url: issuer.to_string(),
+                fetched_at: now(),
+                metadata: metadata.clone(),
+            });
+            Ok(metadata)
+        }
+        Err(e) => cached
+            .map(|c| c.metadata)
+            .ok_or_else(|| io::Error::other(format!("failed to discover endpoints: {e}"))),
+    }
+}
+
+fn fetch(
+    config: &Config,
+    issuer: &str,
+    realm: &str,
+) -> Result<Metadata, Box<dyn std::error::Error>> {
+    let url = format!("{issuer}/.well-known/openid-configuration");
+    let metadata = failover::send(config, &url, |client, url| client.get(url))?
+        .error_for_status()?
+        .json::<Metadata>()?;
+    // A failover node may give its own host, but never another realm
+    if !metadata
+        .issuer
+        .trim_end_matches('/')
+        .ends_with(&format!("/realms/{realm}"))
+    {
+        return Err(format!("metadata is for issuer {}", metadata.issuer).into());
+    }
+    Ok(metadata)
+}
+
+fn read_cached(issuer: &str) -> Option<CachedMetadata> {
+    let cached: CachedMetadata =
+        serde_json::from_str(&fs::read_to_string(METADATA_CACHE_PATH).ok()?).ok()?;
+    (cached.issuer_url == issuer).then_some(cached)
+}
+
+fn write_cached(cached: &CachedMetadata) -> io::Result<()> {
+    file::write_atomically(METADATA_CACHE_PATH, &serde_json::to_vec(cached)?, 0o644)
+}
@@ -0,0 +1,101 @@
+//! Sending requests to whichever Keycloak node is up. The node that last
+//! answered is tried first, and the others in turn if it can't be reached or
+//! has a server error. The node is kept on disk, so that processes that each
+//! send only a few requests don't all start with a node that is down.
+
+use std::{
+    fs, io,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use reqwest::blocking::{Client, RequestBuilder, Response};
+
+use crate::{api::log_warning, config::Config, file};
+
+/// Where the base URL of the node that last answered is kept. It is public,
+/// so readable by all.
+pub const NODE_CACHE_PATH: &str = "/var/cache/auth_keycloak_node";
+
+/// Index of the node that last answered, where 0 is the one in the
+/// configured URLs, or [`UNKNOWN`] until read from disk.
+static LAST_GOOD: AtomicUsize = AtomicUsize::new(UNKNOWN);
+
+const UNKNOWN: usize = usize::MAX;
+
+/// Send a request built by `request` for `url` on each node in turn, until
+/// one answers. If none does, the last failure is returned.
+pub fn send<F>(config: &Config, url: &str, request: F) -> reqwest::Result<Response>
+where
+    F: Fn(&Client, &str) -> RequestBuilder,
+{
+    let client = Client::new();
+    let urls = node_urls(config, url);
+    let first = match LAST_GOOD.load(Ordering::Relaxed) {
+        UNKNOWN => read_last_good(&urls),
+        index => index % urls.len(),
+    };
+    let mut index = first;
+    let mut tries = urls.len();
+    loop {
+        let res = request(&client, &urls[index]).send();
+        tries -= 1;
+        if res
+            .as_ref()
+            .is_ok_and(|res| !res.status().is_server_error())
+        {
+            LAST_GOOD.store(index, Ordering::Relaxed);
+            if index != first {
+                // Processes not running as root can't share it, but still
+                // remember it
+                let _ = write_last_good(origin(&urls[index]));
+            }
+            return res;
+        }
+        if tries == 0 {
+            return res;
+        }
+
+        let reason = match &res {
+            Ok(res) => res.status().to_string(),
+            Err(e) => e.to_string(),
+        };
+        log_warning(&format!(
+            "Keycloak at {} failed ({reason}), trying next",
+            urls[index]
+        ));
+        index = (index + 1) % urls.len();
+    }
+}
+
+/// A URL as it is on each node.
+fn node_urls(config: &Config, url: &str) -> Vec<String> {
+    let path = &url[origin(url).len()..];
+    std::iter::once(url.to_string())
+        .chain(
+            config
+                .failover_urls
+                .iter()
+                .map(|base| format!("{}{path}", base.trim_end_matches('/'))),
+        )
+        .collect()
+}
+
+/// The scheme, host and port of a URL.
+fn origin(url: &str) -> &str {
+    url.find("://")
+        .and_then(|scheme| url[scheme + 3..].find('/').map(|end| scheme + 3 + end))
+        .map_or(url, |end| &url[..end])
+}
+
+/// Get the index of the node that last answered any process, or of the
+/// first node if it isn't known or is no longer configured.
+fn read_last_good(urls: &[String]) -> usize {
+    fs::read_to_string(NODE_CACHE_PATH)
+        .ok()
+        .and_then(|base| urls.iter().position(|url| origin(url) == base.trim()))
+        .unwrap_or(0)
+}
+
+fn write_last_good(base: &str) -> io::Result<()> {
+    file::write_atomically(NODE_CACHE_PATH, base.as_bytes(), 0o644)
+}
@@ -0,0 +1,40 @@
+//! Files kept on disk to share state between processes, such as caches,
+//! and the timestamps they record.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Write a file with the permissions `mode`, through a temporary file renamed
+/// over it, so that readers never see it partially written.
+pub fn write_atomically(path: &str, data: &[u8], mode: u32) -> io::Result<()> {
+    let tmp_path = format!("{path}.{}", process::id());
+    let res = (|| {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(mode)
+            .open(&tmp_path)?;
+        // The mode given on creation is limited by the umask
+        file.set_permissions(fs::Permissions::from_mode(mode))?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    res
+}
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
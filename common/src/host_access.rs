@@ -0,0 +1,153 @@
+//! Restricting which Keycloak users may log in to this host, by hostname or
+//! host group.
+
+use std::error::Error;
+
+use crate::{
+    api::types::UserRepresentation,
+    config::{Config, HostAccess},
+    visibility,
+};
+
+/// Whether a user may log in to this host. They may if any of the patterns in
+/// their attribute, or the names of their roles for the configured client,
+/// match the name of this host or one of its groups.
+pub fn is_allowed<F>(
+    config: &Config,
+    user: &UserRepresentation,
+    debug_log: F,
+) -> Result<bool, Box<dyn Error>>
+where
+    F: Fn(String),
+{
+    let Some(access) = &config.host_access else {
+        return Ok(true);
+    };
+
+    let mut patterns = user
+        .attributes
+        .get(&access.attribute_id)
+        .into_iter()
+        .flatten()
+        .flat_map(|value| value.split(','))
+        .map(|pattern| pattern.trim().to_string())
+        .collect::<Vec<_>>();
+    if let Some(client_id) = &access.client_id {
+        let prefix = format!("{client_id}/");
+        patterns.extend(
+            visibility::get_user_roles(config, &user.id, &[client_id], &debug_log)?
+                .into_iter()
+                .filter_map(|role| role.strip_prefix(&prefix).map(str::to_string)),
+        );
+    }
+
+    let names = host_names(access);
+    debug_log(format!(
+        "Host access patterns for {}: {patterns:?}, host names: {names:?}",
+        user.username
+    ));
+    Ok(patterns.iter().any(|pattern| {
+        names
+            .iter()
+            .any(|name| glob_matches(&pattern.to_lowercase(), name))
+    }))
+}
+
+/// The names this host may be referred to by in patterns: its own name and
+/// those of its groups, in lowercase.
+fn host_names(access: &HostAccess) -> Vec<String> {
+    access
+        .hostname
+        .clone()
+        .or_else(hostname)
+        .into_iter()
+        .chain(access.host_groups.iter().cloned())
+        .map(|name| name.to_lowercase())
+        .collect()
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0)?;
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+/// Match a name against a pattern where `*` matches any run of characters and
+/// `?` any single character.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+
+    // Position after the last `*` and where in the name it was matched up to
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_matches;
+
+    #[test]
+    fn matches_literal_names() {
+        assert!(glob_matches("build01", "build01"));
+        assert!(!glob_matches("build01", "build02"));
+        assert!(!glob_matches("build", "build01"));
+        assert!(!glob_matches("build01", "build"));
+    }
+
+    #[test]
+    fn matches_any_one_character() {
+        assert!(glob_matches("build0?", "build01"));
+        assert!(glob_matches("?uild01", "build01"));
+        assert!(!glob_matches("build0?", "build0"));
+        assert!(!glob_matches("build?", "build01"));
+    }
+
+    #[test]
+    fn matches_any_run_of_characters() {
+        assert!(glob_matches("*", "build01"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("build*", "build01"));
+        assert!(glob_matches("build*", "build"));
+        assert!(glob_matches("*01", "build01"));
+        assert!(glob_matches("b*1", "build01"));
+        assert!(glob_matches("build**", "build01"));
+        assert!(!glob_matches("build*", "web01"));
+        assert!(!glob_matches("*02", "build01"));
+    }
+
+    #[test]
+    fn backtracks_after_a_partial_match() {
+        assert!(glob_matches("*ab", "aab"));
+        assert!(glob_matches("*.example.org", "a.b.example.org"));
+        assert!(glob_matches("a*b*c", "abxbxc"));
+        assert!(glob_matches("*a?c", "abacxabc"));
+        assert!(!glob_matches("a*b*c", "abxbx"));
+        assert!(!glob_matches("*.example.org", "a.example.org.uk"));
+    }
+}
@@ -1,3 +1,11 @@
 pub mod api;
 pub mod config;
+pub mod discovery;
+pub mod failover;
+pub mod file;
+pub mod host_access;
 pub mod token;
+pub mod uid;
+pub mod user;
+pub mod validate;
+pub mod visibility;
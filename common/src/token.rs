@@ -1,7 +1,22 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fs, sync::Mutex};
 
-use reqwest::blocking::Client;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    failover,
+    file::{self, now},
+};
+
+/// Where client access tokens are shared between processes, if enabled.
+pub const TOKEN_CACHE_PATH: &str = "/var/cache/auth_keycloak_token.json";
+
+/// Seconds before a token expires at which it is refreshed.
+const EXPIRY_MARGIN: u64 = 30;
+
+/// The client access tokens most recently used by this process, one for each
+/// realm and client.
+static TOKENS: Mutex<Vec<CachedToken>> = Mutex::new(vec![]);
 
 #[derive(Deserialize)]
 #[serde(untagged)]
@@ -18,26 +33,110 @@ pub enum TokenResponse {
     },
 }
 
-pub fn get_client_access_token(
-    token_url: &str,
-    client_id: &str,
-    client_secret: &str,
-) -> Option<String> {
+#[derive(Serialize, Deserialize, Clone)]
+struct CachedToken {
+    token_url: String,
+    client_id: String,
+    access_token: String,
+    /// Seconds since the Unix epoch at which the token expires.
+    expires_at: u64,
+}
+
+impl CachedToken {
+    fn is_for(&self, config: &Config) -> bool {
+        self.token_url == config.token_url && self.client_id == config.client_id
+    }
+
+    fn is_usable(&self, config: &Config) -> bool {
+        self.is_for(config) && now() + EXPIRY_MARGIN < self.expires_at
+    }
+}
+
+/// Get an access token for the client, reusing the last one until shortly
+/// before it expires.
+pub fn get_client_access_token(config: &Config) -> Option<String> {
+    let mut cached = TOKENS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(token) = cached.iter().find(|t| t.is_usable(config)) {
+        return Some(token.access_token.clone());
+    }
+
+    let mut shared = if config.share_token {
+        read_shared_tokens()
+    } else {
+        vec![]
+    };
+    let token = match shared.iter().find(|t| t.is_usable(config)) {
+        Some(token) => token.clone(),
+        None => {
+            let token = request_client_access_token(config)?;
+            if config.share_token {
+                shared.retain(|t| !t.is_for(config));
+                shared.push(token.clone());
+                let _ = write_shared_tokens(&shared);
+            }
+            token
+        }
+    };
+
+    let access_token = token.access_token.clone();
+    cached.retain(|t| !t.is_for(config));
+    cached.push(token);
+    Some(access_token)
+}
+
+/// Forget a token that Keycloak rejected, such as one revoked before it
+/// expired, so that a new one is requested.
+pub fn forget_client_access_token(config: &Config, access_token: &str) {
+    let mut cached = TOKENS.lock().unwrap_or_else(|e| e.into_inner());
+    cached.retain(|t| t.access_token != access_token);
+
+    if config.share_token {
+        let mut shared = read_shared_tokens();
+        let len = shared.len();
+        shared.retain(|t| t.access_token != access_token);
+        if shared.len() != len {
+            let _ = write_shared_tokens(&shared);
+        }
+    }
+}
+
+fn request_client_access_token(config: &Config) -> Option<CachedToken> {
     let mut form_data = HashMap::new();
     form_data.insert("grant_type", "client_credentials");
 
-    let client = Client::new();
-    let res = client
-        .post(token_url)
-        .basic_auth(client_id, Some(client_secret))
-        .form(&form_data)
-        .send()
-        .ok()?
-        .json::<TokenResponse>()
-        .ok()?;
+    let res = failover::send(config, &config.token_url, |client, url| {
+        client
+            .post(url)
+            .basic_auth(&config.client_id, Some(&config.client_secret))
+            .form(&form_data)
+    })
+    .ok()?
+    .json::<TokenResponse>()
+    .ok()?;
 
     match res {
-        TokenResponse::Success { access_token, .. } => Some(access_token),
+        TokenResponse::Success {
+            access_token,
+            expires_in,
+            ..
+        } => Some(CachedToken {
+            token_url: config.token_url.clone(),
+            client_id: config.client_id.clone(),
+            access_token,
+            expires_at: now() + expires_in as u64,
+        }),
         _ => None,
     }
 }
+
+fn read_shared_tokens() -> Vec<CachedToken> {
+    fs::read_to_string(TOKEN_CACHE_PATH)
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+/// Share tokens with other processes. The file is only readable by root.
+fn write_shared_tokens(tokens: &[CachedToken]) -> std::io::Result<()> {
+    file::write_atomically(TOKEN_CACHE_PATH, &serde_json::to_vec(tokens)?, 0o600)
+}
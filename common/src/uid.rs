@@ -0,0 +1,246 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs, io,
+};
+
+use crate::{
+    api::{self, types::UserRepresentation},
+    config::Config,
+};
+
+const PASSWD_PATH: &str = "/etc/passwd";
+
+/// Somewhere that UIDs may already be in use.
+pub trait UidDatabase {
+    fn used_uids(&self) -> Result<HashSet<libc::uid_t>, Box<dyn Error>>;
+}
+
+/// The local passwd file. This is read directly rather than through NSS,
+/// which would re-enter this module.
+pub struct LocalPasswd;
+
+impl LocalPasswd {
+    /// Get the name and UID of every local account.
+    pub fn accounts() -> io::Result<Vec<(String, libc::uid_t)>> {
+        Ok(fs::read_to_string(PASSWD_PATH)?
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split(':');
+                let name = fields.next()?;
+                let uid = fields.nth(1)?.parse().ok()?;
+                Some((name.to_string(), uid))
+            })
+            .collect())
+    }
+}
+
+impl UidDatabase for LocalPasswd {
+    fn used_uids(&self) -> Result<HashSet<libc::uid_t>, Box<dyn Error>> {
+        Ok(Self::accounts()?.into_iter().map(|(_, uid)| uid).collect())
+    }
+}
+
+/// The UIDs already given to Keycloak users.
+pub struct KeycloakUids<'a, F>(pub &'a Config, pub F);
+
+impl<F> UidDatabase for KeycloakUids<'_, F>
+where
+    F: Fn(String),
+{
+    fn used_uids(&self) -> Result<HashSet<libc::uid_t>, Box<dyn Error>> {
+        let KeycloakUids(config, debug_log) = self;
+        let mut uids = HashSet::new();
+        for user in api::users(config, false, debug_log) {
+            uids.extend(uid_of(config, &user?));
+        }
+        Ok(uids)
+    }
+}
+
+/// Collect the UIDs used in any of `databases`.
+pub fn used_uids(databases: &[&dyn UidDatabase]) -> Result<HashSet<libc::uid_t>, Box<dyn Error>> {
+    let mut used = HashSet::new();
+    for database in databases {
+        used.extend(database.used_uids()?);
+    }
+    Ok(used)
+}
+
+/// Find the lowest UID from `start_uid` to `end_uid` inclusive that is not
+/// in `used`.
+pub fn get_first_available_uid(
+    start_uid: libc::uid_t,
+    end_uid: libc::uid_t,
+    used: &HashSet<libc::uid_t>,
+) -> Result<libc::uid_t, Box<dyn Error>> {
+    (start_uid..=end_uid)
+        .find(|uid| !used.contains(uid))
+        .ok_or_else(|| format!("no UIDs available from {start_uid} to {end_uid}").into())
+}
+
+/// Give a Keycloak user the first available UID and store it in their UID
+/// attribute.
+///
+/// Other hosts may be doing the same for other users at once, so after
+/// writing, the realm is searched for the UID. If anyone else has it, the
+/// next candidate is tried instead. Whichever host writes a UID last always
+/// sees the collision, so at most one user keeps it.
+///
+/// `log` is given a syslog priority and a message.
+pub fn assign_uid<F>(
+    config: &Config,
+    user: &UserRepresentation,
+    log: F,
+) -> Result<libc::uid_t, Box<dyn Error>>
+where
+    F: Fn(libc::c_int, String),
+{
+    let debug_log = |v| log(libc::LOG_DEBUG, v);
+    let mut used = used_uids(&[&LocalPasswd, &KeycloakUids(config, debug_log)])?;
+
+    loop {
+        let uid = get_first_available_uid(
+            config.start_uid.max(config.min_uid),
+            config.end_uid.min(config.max_uid),
+            &used,
+        )?;
+        log(libc::LOG_DEBUG, format!("New UID determined: {uid}"));
+        api::set_user_attribute(
+            config,
+            &user.id,
+            &config.uid_attribute_id,
+            vec![uid.to_string()],
+            debug_log,
+        )?;
+
+        let mut query = HashMap::new();
+        query.insert("q", format!("{}:{uid}", config.uid_attribute_id));
+        let others = api::get_users(config, query, debug_log)?
+            .into_iter()
+            .filter(|other| other.id != user.id && uid_of(config, other) == Some(uid))
+            .map(|other| other.username)
+            .collect::<Vec<_>>();
+        if others.is_empty() {
+            log(
+                libc::LOG_INFO,
+                format!("Assigned UID {uid} to {}", user.username),
+            );
+            return Ok(uid);
+        }
+
+        log(
+            libc::LOG_WARNING,
+            format!(
+                "UID {uid} assigned to {} is also held by {}, trying another",
+                user.username,
+                others.join(", ")
+            ),
+        );
+        used.insert(uid);
+    }
+}
+
+/// Get the Keycloak users with a UID.
+pub fn users_with_uid<F>(
+    config: &Config,
+    uid: libc::uid_t,
+    debug_log: F,
+) -> Result<Vec<UserRepresentation>, Box<dyn Error>>
+where
+    F: Fn(String),
+{
+    match &config.id_mapping {
+        Some(mapping) if !mapping.contains(uid) => Ok(vec![]),
+        // Mapped UIDs can't be searched for, so look through every user
+        Some(_) => api::users(config, false, debug_log)
+            .filter(|user| {
+                user.as_ref()
+                    .map_or(true, |user| uid_of(config, user) == Some(uid))
+            })
+            .collect(),
+        None => {
+            let mut query = HashMap::new();
+            query.insert("q", format!("{}:{uid}", config.uid_attribute_id));
+            api::get_users(config, query, debug_log)
+        }
+    }
+}
+
+/// Get the UID of a Keycloak user, if they have one.
+pub fn uid_of(config: &Config, user: &UserRepresentation) -> Option<libc::uid_t> {
+    match &config.id_mapping {
+        Some(mapping) => Some(mapping.map_id(&user.id)),
+        None => user
+            .attributes
+            .get(&config.uid_attribute_id)?
+            .first()?
+            .parse()
+            .ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, error::Error};
+
+    use super::{UidDatabase, get_first_available_uid, used_uids};
+
+    struct FakeUids(Vec<libc::uid_t>);
+
+    impl UidDatabase for FakeUids {
+        fn used_uids(&self) -> Result<HashSet<libc::uid_t>, Box<dyn Error>> {
+            Ok(self.0.iter().copied().collect())
+        }
+    }
+
+    struct FailingUids;
+
+    impl UidDatabase for FailingUids {
+        fn used_uids(&self) -> Result<HashSet<libc::uid_t>, Box<dyn Error>> {
+            Err("unavailable".into())
+        }
+    }
+
+    #[test]
+    fn skips_local_and_keycloak_uids() {
+        let local = FakeUids(vec![1000, 1002]);
+        let keycloak = FakeUids(vec![1001, 1003]);
+        let used = used_uids(&[&local, &keycloak]).unwrap();
+        assert_eq!(get_first_available_uid(1000, 2000, &used).unwrap(), 1004);
+        // UIDs outside the range don't matter
+        assert_eq!(get_first_available_uid(500, 2000, &used).unwrap(), 500);
+    }
+
+    #[test]
+    fn fails_when_range_is_exhausted() {
+        let used = used_uids(&[&FakeUids((1000..=1009).collect())]).unwrap();
+        assert!(get_first_available_uid(1000, 1009, &used).is_err());
+        assert_eq!(get_first_available_uid(1000, 1010, &used).unwrap(), 1010);
+    }
+
+    #[test]
+    fn fails_when_range_is_empty() {
+        assert!(get_first_available_uid(2000, 1000, &HashSet::new()).is_err());
+        assert_eq!(
+            get_first_available_uid(1000, 1000, &HashSet::new()).unwrap(),
+            1000
+        );
+    }
+
+    #[test]
+    fn reaches_end_of_uid_space() {
+        let used = used_uids(&[&FakeUids(vec![u32::MAX - 1])]).unwrap();
+        assert_eq!(
+            get_first_available_uid(u32::MAX - 1, u32::MAX, &used).unwrap(),
+            u32::MAX
+        );
+        let used = used_uids(&[&FakeUids(vec![u32::MAX - 1, u32::MAX])]).unwrap();
+        assert!(get_first_available_uid(u32::MAX - 1, u32::MAX, &used).is_err());
+    }
+
+    #[test]
+    fn fails_when_a_database_fails() {
+        assert!(used_uids(&[&FakeUids(vec![1000]), &FailingUids]).is_err());
+    }
+}
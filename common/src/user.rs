@@ -0,0 +1,295 @@
+//! Unix account details of Keycloak users, shared by NSS and PAM so that they
+//! agree.
+
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{api::types::UserRepresentation, config::Config};
+
+const SHELLS_PATH: &str = "/etc/shells";
+
+/// The primary GID of a user, from their attribute or else the config. This
+/// is `None` if their attribute is not a GID within the configured bounds.
+pub fn primary_gid(config: &Config, user: &UserRepresentation) -> Option<libc::gid_t> {
+    match user.attribute(&config.primary_gid_attribute_id) {
+        Some(gid) => gid.parse().ok().filter(|gid| config.allows_gid(*gid)),
+        None => Some(config.group_id),
+    }
+}
+
+/// The home directory of a user, from their attribute or else the configured
+/// template, or under the configured parent if there is none. This is `None`
+/// if their attribute is not inside the configured parent, or the template
+/// can't safely be filled in for the user.
+pub fn home_directory(config: &Config, user: &UserRepresentation) -> Option<PathBuf> {
+    match (
+        user.attribute(&config.home_attribute_id),
+        &config.home_template,
+    ) {
+        (Some(home), _) => {
+            Some(PathBuf::from(home)).filter(|home| is_inside(home, &config.home_directory_parent))
+        }
+        (None, Some(template)) => render_path(template, config, user).map(PathBuf::from),
+        (None, None) => Some(
+            config
+                .home_directory_parent
+                .join(config.unix_name(&user.username)),
+        ),
+    }
+}
+
+/// Whether a home directory is an absolute path that can't leave the
+/// directory it names through `..`.
+pub fn is_safe_home(home: &Path) -> bool {
+    home.is_absolute() && !home.components().any(|c| c == Component::ParentDir)
+}
+
+/// Whether a home directory is below a parent directory, rather than the
+/// parent itself or a system directory that its owner could take over.
+fn is_inside(home: &Path, parent: &Path) -> bool {
+    is_safe_home(home) && home != parent && home.starts_with(parent)
+}
+
+/// Read the login shells listed in `/etc/shells`.
+pub fn login_shells() -> io::Result<HashSet<String>> {
+    Ok(fs::read_to_string(SHELLS_PATH)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// The GECOS field of a user, from the configured template, or their
+/// username if that leaves it blank.
+pub fn gecos(config: &Config, user: &UserRepresentation) -> String {
+    let gecos = render(&config.gecos_template, config, user);
+    match gecos.trim() {
+        "" => user.username.clone(),
+        gecos => gecos.to_string(),
+    }
+}
+
+/// The login shell of a user, from their attribute or else the config.
+pub fn shell(config: &Config, user: &UserRepresentation) -> String {
+    user.attribute(&config.shell_attribute_id)
+        .unwrap_or(&config.shell)
+        .to_string()
+}
+
+/// Fill in a template with details of a user. The placeholders are:
+///
+/// - `%u`: username
+/// - `%f`: first name
+/// - `%l`: last name
+/// - `%e`: email address
+/// - `%i`: Keycloak ID
+/// - `%realm`: realm
+/// - `%{name}`: the first value of the attribute `name`
+/// - `%%`: a literal `%`
+///
+/// Missing values are left empty, and anything else is copied as it is.
+pub fn render(template: &str, config: &Config, user: &UserRepresentation) -> String {
+    substitute(template, config, user, |_| true).unwrap_or_default()
+}
+
+/// Fill in a template for a path, like [`render`]. This is `None` if any
+/// value is missing, contains `/` or is `.` or `..`, as the path could then
+/// leave the directory it names.
+pub fn render_path(template: &str, config: &Config, user: &UserRepresentation) -> Option<String> {
+    substitute(template, config, user, |value| {
+        !value.is_empty() && !value.contains('/') && value != "." && value != ".."
+    })
+}
+
+/// Fill in a template, as long as every value passes `is_allowed`.
+fn substitute<F>(
+    template: &str,
+    config: &Config,
+    user: &UserRepresentation,
+    is_allowed: F,
+) -> Option<String>
+where
+    F: Fn(&str) -> bool,
+{
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('%') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let (value, len) = if rest.starts_with("realm") {
+            (config.realm.as_str(), "realm".len())
+        } else if let Some((name, _)) = rest.strip_prefix('{').and_then(|r| r.split_once('}')) {
+            (user.attribute(name).unwrap_or_default(), name.len() + 2)
+        } else {
+            match rest.chars().next() {
+                Some('u') => (user.username.as_str(), 1),
+                Some('f') => (user.first_name.as_deref().unwrap_or_default(), 1),
+                Some('l') => (user.last_name.as_deref().unwrap_or_default(), 1),
+                Some('e') => (user.email.as_deref().unwrap_or_default(), 1),
+                Some('i') => (user.id.as_str(), 1),
+                Some('%') => {
+                    out.push('%');
+                    rest = &rest[1..];
+                    continue;
+                }
+                _ => {
+                    out.push('%');
+                    continue;
+                }
+            }
+        };
+        if !is_allowed(value) {
+            return None;
+        }
+        out.push_str(value);
+        rest = &rest[len..];
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use serde_json::json;
+
+    use super::{gecos, home_directory, is_safe_home, render, render_path};
+    use crate::{api::types::UserRepresentation, config::Config};
+
+    fn config() -> Config {
+        Config {
+            realm: "staff".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn user(first_name: &str, last_name: &str) -> UserRepresentation {
+        serde_json::from_value(json!({
+            "id": "6f1c0a4e",
+            "username": "alice",
+            "firstName": first_name,
+            "lastName": last_name,
+            "email": "alice@example.com",
+            "attributes": { "phone": ["555-0100"], "empty": [""] },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let user = user("Alice", "Smith");
+        assert_eq!(
+            render("%u %f %l %e %i %realm %{phone}", &config(), &user),
+            "alice Alice Smith alice@example.com 6f1c0a4e staff 555-0100"
+        );
+    }
+
+    #[test]
+    fn renders_missing_values_as_empty() {
+        let user = serde_json::from_value(json!({ "id": "1", "username": "bob" })).unwrap();
+        assert_eq!(
+            render("[%f][%l][%e][%{phone}]", &config(), &user),
+            "[][][][]"
+        );
+    }
+
+    #[test]
+    fn copies_unknown_placeholders() {
+        let user = user("Alice", "Smith");
+        assert_eq!(
+            render("100%% %x %{open %", &config(), &user),
+            "100% %x %{open %"
+        );
+    }
+
+    #[test]
+    fn renders_safe_paths() {
+        let user = user("Alice", "Smith");
+        assert_eq!(
+            render_path("/home/%realm/%u", &config(), &user).as_deref(),
+            Some("/home/staff/alice")
+        );
+        // Literal text is the admin's choice
+        assert_eq!(
+            render_path("/srv/../home/%%%u", &config(), &user).as_deref(),
+            Some("/srv/../home/%alice")
+        );
+    }
+
+    #[test]
+    fn rejects_unsafe_path_values() {
+        for (first_name, last_name) in [
+            ("Alice", "../../etc"),
+            ("a/b", "Smith"),
+            ("Alice", ".."),
+            ("Alice", "."),
+            ("Alice", ""),
+        ] {
+            let user = user(first_name, last_name);
+            assert_eq!(render_path("/home/%f.%l", &config(), &user), None);
+        }
+        assert_eq!(
+            render_path("/home/%{empty}", &config(), &user("a", "b")),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_unsafe_home_templates() {
+        let config = Config {
+            home_template: Some("/home/%l".to_string()),
+            ..config()
+        };
+        assert_eq!(home_directory(&config, &user("Alice", "../../etc")), None);
+        assert_eq!(
+            home_directory(&config, &user("Alice", "Smith")),
+            Some(PathBuf::from("/home/Smith"))
+        );
+    }
+
+    #[test]
+    fn keeps_home_attributes_inside_parent() {
+        let home = |home: &str| {
+            let user = serde_json::from_value(json!({
+                "id": "1",
+                "username": "alice",
+                "attributes": { "homeDirectory": [home] },
+            }))
+            .unwrap();
+            home_directory(&config(), &user)
+        };
+        assert_eq!(home("/home/alice"), Some(PathBuf::from("/home/alice")));
+        assert_eq!(
+            home("/home/staff/alice"),
+            Some(PathBuf::from("/home/staff/alice"))
+        );
+        assert_eq!(home("/home"), None);
+        assert_eq!(home("/home/../root"), None);
+        assert_eq!(home("/homework/alice"), None);
+        assert_eq!(home("/etc/systemd/system/sshd.service.d"), None);
+        assert_eq!(home("/root/.config"), None);
+    }
+
+    #[test]
+    fn checks_home_directories() {
+        assert!(is_safe_home(Path::new("/home/alice")));
+        assert!(!is_safe_home(Path::new("home/alice")));
+        assert!(!is_safe_home(Path::new("/home/../etc")));
+    }
+
+    #[test]
+    fn falls_back_to_username_for_blank_gecos() {
+        let config = Config {
+            gecos_template: " %f ".to_string(),
+            ..config()
+        };
+        assert_eq!(gecos(&config, &user("", "Smith")), "alice");
+        assert_eq!(gecos(&config, &user("Alice", "Smith")), "Alice");
+    }
+}
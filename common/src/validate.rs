@@ -0,0 +1,254 @@
+//! Checks on Keycloak users before they are used as Unix accounts, so that
+//! they can't produce malformed entries or take over local accounts.
+
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    ffi::c_int,
+    path::Path,
+};
+
+use crate::{
+    api::{
+        self,
+        types::{GroupRepresentation, UserRepresentation},
+    },
+    config::{Config, DisabledUsers},
+    uid, user,
+};
+
+/// Longest user or group name accepted, as for `useradd`.
+const MAX_NAME_LEN: usize = 32;
+
+pub struct Validator<'a, F> {
+    config: &'a Config,
+    log: F,
+    local_names: HashSet<String>,
+    local_uids: HashSet<libc::uid_t>,
+    login_shells: HashSet<String>,
+    /// UIDs shared by Keycloak users, with the ID of the one that keeps it,
+    /// if any.
+    duplicate_uids: HashMap<libc::uid_t, Option<String>>,
+    /// UIDs whose holders have been searched for, or `None` once every user
+    /// has been checked.
+    checked_uids: Option<HashSet<libc::uid_t>>,
+}
+
+impl<'a, F: Fn(c_int, String)> Validator<'a, F> {
+    /// Read the local accounts, to check any number of users against.
+    pub fn new(config: &'a Config, log: F) -> Self {
+        let accounts = uid::LocalPasswd::accounts().unwrap_or_else(|e| {
+            log(
+                libc::LOG_WARNING,
+                format!("Failed to read local accounts: {e}"),
+            );
+            vec![]
+        });
+        let login_shells = user::login_shells().unwrap_or_else(|e| {
+            log(
+                libc::LOG_WARNING,
+                format!("Failed to read login shells: {e}"),
+            );
+            HashSet::new()
+        });
+        Self {
+            config,
+            log,
+            login_shells,
+            local_uids: accounts.iter().map(|(_, uid)| *uid).collect(),
+            local_names: accounts.into_iter().map(|(name, _)| name).collect(),
+            duplicate_uids: HashMap::new(),
+            checked_uids: Some(HashSet::new()),
+        }
+    }
+
+    /// Read the local accounts, and find UIDs shared by Keycloak users.
+    pub fn with_users(config: &'a Config, users: &[UserRepresentation], log: F) -> Self {
+        let mut validator = Self::new(config, log);
+        validator.find_duplicates(users);
+        validator
+    }
+
+    /// Find UIDs shared by Keycloak users, so that none of their holders are
+    /// shown. Mapped IDs can collide by chance, so the UID is then kept by
+    /// the oldest user, rather than a new user hiding an existing one.
+    pub fn find_duplicates(&mut self, users: &[UserRepresentation]) {
+        let mut holders = HashMap::<_, Vec<_>>::new();
+        for user in users {
+            if let Some(uid) = uid::uid_of(self.config, user) {
+                holders.entry(uid).or_default().push(user);
+            }
+        }
+        for (uid, mut holders) in holders.into_iter().filter(|(_, h)| h.len() > 1) {
+            holders.sort_by_key(|user| (user.created_timestamp.unwrap_or(i64::MAX), &user.id));
+            let names = holders
+                .iter()
+                .map(|user| user.username.as_str())
+                .collect::<Vec<_>>();
+            (self.log)(
+                libc::LOG_ERR,
+                format!("UID {uid} is shared by Keycloak users {}", names.join(", ")),
+            );
+            let keeper = self
+                .config
+                .id_mapping
+                .as_ref()
+                .map(|_| holders[0].id.clone());
+            self.duplicate_uids.insert(uid, keeper);
+        }
+    }
+
+    /// Find the Keycloak users sharing a user's UID, so that a single user can
+    /// be checked without listing every user. Each UID is searched for once.
+    pub fn find_duplicates_of(&mut self, user: &UserRepresentation) -> Result<(), Box<dyn Error>> {
+        let Some(uid) = uid::uid_of(self.config, user) else {
+            return Ok(());
+        };
+        let Some(checked_uids) = &mut self.checked_uids else {
+            return Ok(());
+        };
+        if !checked_uids.insert(uid) {
+            return Ok(());
+        }
+        if self
+            .config
+            .id_mapping
+            .as_ref()
+            .is_some_and(|mapping| mapping.contains(uid))
+        {
+            // Mapped UIDs can't be searched for, so check every user at once
+            return self.find_all_duplicates();
+        }
+        let holders = uid::users_with_uid(self.config, uid, |v| (self.log)(libc::LOG_DEBUG, v))?;
+        self.find_duplicates(&holders);
+        Ok(())
+    }
+
+    /// Find UIDs shared by any Keycloak users, by listing every user once.
+    pub fn find_all_duplicates(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.checked_uids.is_none() {
+            return Ok(());
+        }
+        let users = api::users(self.config, false, |v| (self.log)(libc::LOG_DEBUG, v))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.find_duplicates(&users);
+        self.checked_uids = None;
+        Ok(())
+    }
+
+    /// Get the UID of a user, if they have one and may be shown.
+    pub fn uid(&self, user: &UserRepresentation) -> Option<libc::uid_t> {
+        let uid = uid::uid_of(self.config, user)?;
+        self.is_valid(user, uid).then_some(uid)
+    }
+
+    /// Whether a user may be shown with a given UID, logging why not.
+    pub fn is_valid(&self, user: &UserRepresentation, uid: libc::uid_t) -> bool {
+        if !self.is_valid_name(user) {
+            return false;
+        }
+        if !self.config.allows_uid(uid) {
+            self.hide(
+                user,
+                format!(
+                    "UID {uid} is outside {}-{}",
+                    self.config.min_uid, self.config.max_uid
+                ),
+            );
+            return false;
+        }
+        if let Some(keeper) = self.duplicate_uids.get(&uid)
+            && keeper.as_deref() != Some(user.id.as_str())
+        {
+            self.hide(user, format!("UID {uid} is shared with another user"));
+            return false;
+        }
+        if self.local_uids.contains(&uid) {
+            self.hide(user, format!("UID {uid} belongs to a local account"));
+            return false;
+        }
+        true
+    }
+
+    /// Whether a user may be shown with any UID, logging why not.
+    pub fn is_valid_name(&self, user: &UserRepresentation) -> bool {
+        let reason = if !user.enabled && self.config.disabled_users == DisabledUsers::Hide {
+            "disabled".to_string()
+        } else if !is_portable_name(&user.username) {
+            "not a portable username".to_string()
+        } else if self
+            .local_names
+            .contains(&self.config.unix_name(&user.username))
+        {
+            "name belongs to a local account".to_string()
+        } else if user::primary_gid(self.config, user).is_none() {
+            "invalid primary GID".to_string()
+        } else if !user::home_directory(self.config, user).is_some_and(|home| is_valid_home(&home))
+        {
+            "invalid home directory".to_string()
+        } else if !is_valid_field(&user::shell(self.config, user)) {
+            "invalid shell".to_string()
+        } else if user
+            .attribute(&self.config.shell_attribute_id)
+            .is_some_and(|shell| !self.login_shells.contains(shell))
+        {
+            "shell not in /etc/shells".to_string()
+        } else {
+            return true;
+        };
+        self.hide(user, reason);
+        false
+    }
+
+    fn hide(&self, user: &UserRepresentation, reason: String) {
+        (self.log)(
+            libc::LOG_WARNING,
+            format!("Hiding Keycloak user {:?}: {reason}", user.username),
+        );
+    }
+}
+
+/// Whether a group may be shown, logging why not.
+pub fn is_valid_group<F: Fn(c_int, String)>(group: &GroupRepresentation, log: F) -> bool {
+    if is_portable_name(&group.name) {
+        return true;
+    }
+    log(
+        libc::LOG_WARNING,
+        format!(
+            "Hiding Keycloak group {:?}: not a portable name",
+            group.path
+        ),
+    );
+    false
+}
+
+/// Whether a user or group name only uses the POSIX portable filename
+/// characters, and doesn't start with a hyphen.
+fn is_portable_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with('-')
+        && name != "."
+        && name != ".."
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b))
+}
+
+fn is_valid_home(home: &Path) -> bool {
+    user::is_safe_home(home) && is_valid_field(&home.to_string_lossy())
+}
+
+/// Whether a value can be put in a passwd field as it is.
+fn is_valid_field(value: &str) -> bool {
+    !value.contains(|c: char| c == ':' || c.is_control())
+}
+
+/// Make a free text value safe to put in a passwd field.
+pub fn sanitise(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c == ':' || c.is_control() { ' ' } else { c })
+        .collect()
+}
@@ -0,0 +1,225 @@
+//! Restricting which Keycloak users are visible through NSS and may log in
+//! through PAM to the members of some groups and holders of some roles, and
+//! optionally hiding users from NSS on hosts they may not log in to.
+
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+};
+
+use crate::{
+    api::{self, types::UserRepresentation},
+    config::Config,
+    host_access,
+};
+
+/// Whether only some users are visible.
+pub fn is_restricted(config: &Config) -> bool {
+    is_restricted_to_members(config) || is_restricted_to_host(config)
+}
+
+fn is_restricted_to_members(config: &Config) -> bool {
+    !config.allowed_groups.is_empty() || !config.allowed_roles.is_empty()
+}
+
+fn is_restricted_to_host(config: &Config) -> bool {
+    config.host_access.as_ref().is_some_and(|access| access.nss)
+}
+
+/// Whether a user is a member of an allowed group or one of its subgroups,
+/// or has an allowed role, including through composite roles and groups. If
+/// set, they must also be allowed to log in to this host.
+pub fn is_allowed<F>(
+    config: &Config,
+    user: &UserRepresentation,
+    debug_log: F,
+) -> Result<bool, Box<dyn Error>>
+where
+    F: Fn(String),
+{
+    if is_restricted_to_host(config) && !host_access::is_allowed(config, user, &debug_log)? {
+        return Ok(false);
+    }
+    if !is_restricted_to_members(config) {
+        return Ok(true);
+    }
+    let user_id = user.id.as_str();
+
+    if !config.allowed_groups.is_empty() {
+        for group in api::user_groups(config, user_id, &debug_log) {
+            let group = group?;
+            if config
+                .allowed_groups
+                .iter()
+                .any(|allowed| is_in_group(&group.path, allowed))
+            {
+                return Ok(true);
+            }
+        }
+    }
+
+    if !config.allowed_roles.is_empty() {
+        let client_ids = config
+            .allowed_roles
+            .iter()
+            .filter_map(|role| role.split_once('/').map(|(client_id, _)| client_id))
+            .collect::<Vec<_>>();
+        let roles = get_user_roles(config, user_id, &client_ids, &debug_log)?;
+        if roles.iter().any(|role| config.allowed_roles.contains(role)) {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Whether a user may log in to this host: they must be allowed as for
+/// [`is_allowed`], and on this host even if NSS doesn't hide users from
+/// other hosts.
+pub fn may_log_in<F>(
+    config: &Config,
+    user: &UserRepresentation,
+    debug_log: F,
+) -> Result<bool, Box<dyn Error>>
+where
+    F: Fn(String),
+{
+    Ok(is_allowed(config, user, &debug_log)?
+        && (is_restricted_to_host(config) || host_access::is_allowed(config, user, &debug_log)?))
+}
+
+/// Get every visible user. If only members of some groups and roles are,
+/// they are found through these rather than by listing the whole realm, so
+/// only users given an allowed role directly are included.
+pub fn users<F>(config: &Config, debug_log: F) -> Result<Vec<UserRepresentation>, Box<dyn Error>>
+where
+    F: Fn(String),
+{
+    let users = if is_restricted_to_members(config) {
+        members(config, &debug_log)?
+    } else {
+        api::users(config, false, &debug_log).collect::<Result<_, _>>()?
+    };
+    if !is_restricted_to_host(config) {
+        return Ok(users);
+    }
+
+    let mut allowed = vec![];
+    for user in users {
+        if host_access::is_allowed(config, &user, &debug_log)? {
+            allowed.push(user);
+        }
+    }
+    Ok(allowed)
+}
+
+/// Get the members of the allowed groups and holders of the allowed roles.
+fn members<F>(config: &Config, debug_log: F) -> Result<Vec<UserRepresentation>, Box<dyn Error>>
+where
+    F: Fn(String),
+{
+    let mut seen = HashSet::new();
+    let mut users = vec![];
+    let mut add = |found: Result<Vec<UserRepresentation>, Box<dyn Error>>| match found {
+        Ok(found) => {
+            users.extend(
+                found
+                    .into_iter()
+                    .filter(|user| seen.insert(user.id.clone())),
+            );
+            Ok(())
+        }
+        // Groups and roles that don't exist have no members
+        Err(e) if api::is_not_found(&*e) => Ok(()),
+        Err(e) => Err(e),
+    };
+
+    for path in &config.allowed_groups {
+        add(group_members(config, path, &debug_log))?;
+    }
+    for role in &config.allowed_roles {
+        let found = match role.split_once('/') {
+            Some((client_id, role)) => match api::find_client(config, client_id, &debug_log)? {
+                Some(client) => {
+                    api::role_users(config, Some(&client.id), role, &debug_log).collect()
+                }
+                None => Ok(vec![]),
+            },
+            None => api::role_users(config, None, role, &debug_log).collect(),
+        };
+        add(found)?;
+    }
+
+    Ok(users)
+}
+
+/// Get the effective realm roles of a user, and their effective roles for
+/// some clients as `client_id/role`.
+pub fn get_user_roles<F>(
+    config: &Config,
+    user_id: &str,
+    client_ids: &[&str],
+    debug_log: F,
+) -> Result<Vec<String>, Box<dyn Error>>
+where
+    F: Fn(String),
+{
+    // Effective roles are not paged
+    let query = HashMap::<&str, &str>::new();
+
+    let mut roles = api::get_user_realm_roles(config, user_id, query.clone(), &debug_log)?
+        .into_iter()
+        .map(|role| role.name)
+        .collect::<Vec<_>>();
+
+    let mut client_ids = client_ids.to_vec();
+    client_ids.sort_unstable();
+    client_ids.dedup();
+
+    for client_id in client_ids {
+        let Some(client) = api::find_client(config, client_id, &debug_log)? else {
+            debug_log(format!("Client {client_id} does not exist"));
+            continue;
+        };
+        roles.extend(
+            api::get_user_client_roles(config, user_id, &client.id, query.clone(), &debug_log)?
+                .into_iter()
+                .map(|role| format!("{client_id}/{}", role.name)),
+        );
+    }
+
+    Ok(roles)
+}
+
+/// Get the members of a group and all of its subgroups.
+fn group_members<F>(
+    config: &Config,
+    path: &str,
+    debug_log: F,
+) -> Result<Vec<UserRepresentation>, Box<dyn Error>>
+where
+    F: Fn(String),
+{
+    let mut pending = vec![api::get_group_by_path(config, path, &debug_log)?];
+    let mut members = vec![];
+    while let Some(mut group) = pending.pop() {
+        members.extend(
+            api::group_members(config, &group.id, &debug_log).collect::<Result<Vec<_>, _>>()?,
+        );
+        let mut children = std::mem::take(&mut group.sub_groups);
+        if children.is_empty() && group.sub_group_count.is_some_and(|c| c > 0) {
+            children =
+                api::group_children(config, &group.id, &debug_log).collect::<Result<_, _>>()?;
+        }
+        pending.extend(children);
+    }
+    Ok(members)
+}
+
+/// Whether a group path is that of an allowed group or one of its subgroups.
+fn is_in_group(path: &str, allowed: &str) -> bool {
+    let allowed = allowed.trim_matches('/');
+    path.trim_start_matches('/')
+        .strip_prefix(allowed)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
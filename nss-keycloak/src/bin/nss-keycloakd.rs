@@ -0,0 +1,9 @@
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    if let Err(e) = nss_keycloak::daemon::run() {
+        eprintln!("nss-keycloakd: {e}");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
@@ -1,16 +1,37 @@
-use std::{fs, os::unix::fs::PermissionsExt};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+};
 
-use libnss::passwd::Passwd;
+use common::file::{self, now};
+use libnss::{group::Group as NssGroup, passwd::Passwd, shadow::Shadow as NssShadow};
 use serde::{Deserialize, Serialize};
 
-const CACHE_PATH: &str = "/var/cache/auth_keycloak.toml";
+const CACHE_PATH: &str = "/var/cache/auth_keycloak.db";
+/// The cache from before it was indexed, which is migrated from if found.
+const TOML_CACHE_PATH: &str = "/var/cache/auth_keycloak.toml";
+const LOCK_PATH: &str = "/var/cache/auth_keycloak.lock";
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct Cache {
     pub user: Vec<User>,
+    #[serde(default)]
+    pub group: Vec<Group>,
+    #[serde(default)]
+    pub shadow: Vec<Shadow>,
+    /// Supplementary GIDs of each user, keyed by username.
+    #[serde(default)]
+    pub user_groups: HashMap<String, Vec<libc::gid_t>>,
+    /// Users recently looked up and not found in Keycloak.
+    #[serde(default)]
+    pub missing: Vec<Miss>,
+    /// Groups recently looked up and not found in Keycloak.
+    #[serde(default)]
+    pub missing_groups: Vec<GroupMiss>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct User {
     pub uid: libc::uid_t,
     pub username: String,
@@ -18,6 +39,12 @@ pub struct User {
     pub gid: libc::uid_t,
     pub home_dir: String,
     pub shell: String,
+    /// Seconds since the Unix epoch at which this entry was fetched.
+    #[serde(default)]
+    pub cached_at: u64,
+    /// The realm the user was found in.
+    #[serde(default)]
+    pub realm: String,
 }
 
 impl From<&Passwd> for User {
@@ -29,10 +56,39 @@ impl From<&Passwd> for User {
             home_dir: passwd.dir.clone(),
             name: passwd.gecos.clone(),
             shell: passwd.shell.clone(),
+            cached_at: now(),
+            realm: String::new(),
         }
     }
 }
 
+/// How a user was looked up.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Key {
+    Uid(libc::uid_t),
+    Name(String),
+}
+
+impl Key {
+    pub fn matches(&self, user: &User) -> bool {
+        match self {
+            Key::Uid(uid) => user.uid == *uid,
+            Key::Name(name) => user.username == *name,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Miss {
+    pub key: Key,
+    /// Seconds since the Unix epoch at which the lookup was made.
+    pub cached_at: u64,
+    /// The realm the user was not found in.
+    #[serde(default)]
+    pub realm: String,
+}
+
 impl From<User> for Passwd {
     fn from(value: User) -> Self {
         Passwd {
@@ -47,31 +103,319 @@ impl From<User> for Passwd {
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Group {
+    pub gid: libc::gid_t,
+    pub name: String,
+    pub members: Vec<String>,
+    /// Seconds since the Unix epoch at which this entry was fetched.
+    #[serde(default)]
+    pub cached_at: u64,
+    #[serde(default)]
+    pub realm: String,
+}
+
+impl From<&NssGroup> for Group {
+    fn from(group: &NssGroup) -> Self {
+        Group {
+            gid: group.gid,
+            name: group.name.clone(),
+            members: group.members.clone(),
+            cached_at: now(),
+            realm: String::new(),
+        }
+    }
+}
+
+/// How a group was looked up.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupKey {
+    Gid(libc::gid_t),
+    Name(String),
+}
+
+impl GroupKey {
+    pub fn matches(&self, group: &Group) -> bool {
+        match self {
+            GroupKey::Gid(gid) => group.gid == *gid,
+            GroupKey::Name(name) => group.name == *name,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GroupMiss {
+    pub key: GroupKey,
+    /// Seconds since the Unix epoch at which the lookup was made.
+    pub cached_at: u64,
+    /// The realm the group was not found in.
+    pub realm: String,
+}
+
+impl From<Group> for NssGroup {
+    fn from(value: Group) -> Self {
+        NssGroup {
+            gid: value.gid,
+            name: value.name,
+            passwd: "x".to_string(),
+            members: value.members,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Shadow {
+    pub name: String,
+    pub last_change: isize,
+    pub max_days: isize,
+    pub expire_date: isize,
+    #[serde(default)]
+    pub realm: String,
+}
+
+impl From<&NssShadow> for Shadow {
+    fn from(shadow: &NssShadow) -> Self {
+        Shadow {
+            name: shadow.name.clone(),
+            last_change: shadow.last_change,
+            max_days: shadow.change_max_days,
+            expire_date: shadow.expire_date,
+            realm: String::new(),
+        }
+    }
+}
+
+impl From<Shadow> for NssShadow {
+    fn from(value: Shadow) -> Self {
+        NssShadow {
+            name: value.name,
+            passwd: "!".to_string(),
+            last_change: value.last_change,
+            change_min_days: -1,
+            change_max_days: value.max_days,
+            change_warn_days: -1,
+            change_inactive_days: -1,
+            expire_date: value.expire_date,
+            reserved: usize::MAX,
+        }
+    }
+}
+
+mod db;
+
+/// Read the whole cache.
 pub fn cache() -> Option<Cache> {
-    if let Ok(data) = fs::read_to_string(CACHE_PATH) {
-        toml::from_str(&data).ok()
-    } else {
-        None
+    match db::Db::open(CACHE_PATH) {
+        Ok(Some(db)) => db.to_cache().ok(),
+        // Written by an incompatible version, so start again
+        Ok(None) => None,
+        // Migrate from the old cache if there is one
+        Err(_) => fs::read_to_string(TOML_CACHE_PATH)
+            .ok()
+            .and_then(|data| toml::from_str(&data).ok()),
     }
 }
 
-pub fn update_cache(users: &[User]) {
-    let mut cache = cache().unwrap_or_default();
-    for user in users {
-        // Remove this UID
-        let mut new_users = cache
-            .user
+/// Find a single user, without reading the whole cache if possible.
+pub fn user(key: &Key) -> Option<User> {
+    match db::Db::open(CACHE_PATH) {
+        Ok(Some(db)) => db.user(key).ok().flatten(),
+        Ok(None) => None,
+        Err(_) => cache()?.user.into_iter().find(|u| key.matches(u)),
+    }
+}
+
+/// Users recently looked up and not found.
+pub fn misses() -> Vec<Miss> {
+    match db::Db::open(CACHE_PATH) {
+        Ok(Some(db)) => db.misses().unwrap_or_default(),
+        Ok(None) => vec![],
+        Err(_) => cache().map(|c| c.missing).unwrap_or_default(),
+    }
+}
+
+/// Whether something cached at `cached_at` is less than `ttl` seconds old.
+pub fn is_fresh(cached_at: u64, ttl: u64) -> bool {
+    now().saturating_sub(cached_at) < ttl
+}
+
+/// Record that a user was not found in a realm, forgetting misses older than
+/// `ttl` seconds.
+pub fn record_missing(realm: &str, key: Key, ttl: u64) {
+    modify(|cache| {
+        // Forget everything about the user, so it can't be used offline
+        let (gone, users) = std::mem::take(&mut cache.user)
             .into_iter()
-            .filter(|u| u.uid != user.uid)
-            .collect::<Vec<_>>();
+            .partition::<Vec<_>, _>(|u| u.realm == realm && key.matches(u));
+        cache.user = users;
+        for user in gone {
+            cache
+                .shadow
+                .retain(|s| s.realm != realm || s.name != user.username);
+            cache.user_groups.remove(&user.username);
+        }
+
+        let now = now();
+        cache.missing.retain(|m| {
+            (m.realm != realm || m.key != key) && now.saturating_sub(m.cached_at) < ttl
+        });
+        cache.missing.push(Miss {
+            key,
+            cached_at: now,
+            realm: realm.to_string(),
+        });
+    });
+}
+
+pub fn update_cache(realm: &str, users: &[User]) {
+    modify(|cache| {
+        for user in users {
+            // Forget that this user was missing
+            cache
+                .missing
+                .retain(|m| m.realm != realm || !m.key.matches(user));
+
+            // Replace any user with this UID
+            cache.user.retain(|u| u.uid != user.uid);
+            cache.user.push(User {
+                realm: realm.to_string(),
+                ..user.clone()
+            });
+        }
+    });
+}
+
+/// Replace every cached user of a realm, so that users removed from Keycloak
+/// are forgotten.
+pub fn replace_users(realm: &str, users: &[User]) {
+    modify(|cache| {
+        cache
+            .missing
+            .retain(|m| m.realm != realm || !users.iter().any(|u| m.key.matches(u)));
+        cache.user.retain(|u| u.realm != realm);
+        cache.user.extend(users.iter().map(|u| User {
+            realm: realm.to_string(),
+            ..u.clone()
+        }));
+        let user = &cache.user;
+        cache
+            .user_groups
+            .retain(|username, _| user.iter().any(|u| u.username == *username));
+    });
+}
+
+/// Record that a group was not found in a realm, forgetting misses older than
+/// `ttl` seconds.
+pub fn record_missing_group(realm: &str, key: GroupKey, ttl: u64) {
+    modify(|cache| {
+        cache.group.retain(|g| g.realm != realm || !key.matches(g));
+
+        let now = now();
+        cache.missing_groups.retain(|m| {
+            (m.realm != realm || m.key != key) && now.saturating_sub(m.cached_at) < ttl
+        });
+        cache.missing_groups.push(GroupMiss {
+            key,
+            cached_at: now,
+            realm: realm.to_string(),
+        });
+    });
+}
+
+pub fn update_group_cache(realm: &str, groups: &[Group]) {
+    modify(|cache| {
+        for group in groups {
+            // Forget that this group was missing
+            cache
+                .missing_groups
+                .retain(|m| m.realm != realm || !m.key.matches(group));
+
+            // Replace any group of the realm with this GID
+            cache
+                .group
+                .retain(|g| g.realm != realm || g.gid != group.gid);
+            cache.group.push(Group {
+                realm: realm.to_string(),
+                ..group.clone()
+            });
+        }
+    });
+}
+
+/// Replace every cached group of a realm, so that groups removed from
+/// Keycloak are forgotten.
+pub fn replace_groups(realm: &str, groups: &[Group]) {
+    modify(|cache| {
+        cache
+            .missing_groups
+            .retain(|m| m.realm != realm || !groups.iter().any(|g| m.key.matches(g)));
+        cache.group.retain(|g| g.realm != realm);
+        cache.group.extend(groups.iter().map(|g| Group {
+            realm: realm.to_string(),
+            ..g.clone()
+        }));
+    });
+}
+
+pub fn update_shadow_cache(realm: &str, shadows: &[Shadow]) {
+    modify(|cache| {
+        for shadow in shadows {
+            cache.shadow.retain(|s| s.name != shadow.name);
+            cache.shadow.push(Shadow {
+                realm: realm.to_string(),
+                ..shadow.clone()
+            });
+        }
+    });
+}
+
+/// Replace every cached shadow entry of a realm, so that users removed from
+/// Keycloak are forgotten.
+pub fn replace_shadows(realm: &str, shadows: &[Shadow]) {
+    modify(|cache| {
+        cache.shadow.retain(|s| s.realm != realm);
+        cache.shadow.extend(shadows.iter().map(|s| Shadow {
+            realm: realm.to_string(),
+            ..s.clone()
+        }));
+    });
+}
+
+pub fn update_user_groups_cache(username: &str, gids: &[libc::gid_t]) {
+    modify(|cache| {
+        cache
+            .user_groups
+            .insert(username.to_string(), gids.to_vec());
+    });
+}
+
+/// Change the cache while holding an exclusive lock, so that concurrent
+/// changes are not lost. Failures are ignored, as the cache is only a
+/// fallback and is not writable by unprivileged users.
+fn modify<F>(f: F)
+where
+    F: FnOnce(&mut Cache),
+{
+    let _ = try_modify(f);
+}
+
+fn try_modify<F>(f: F) -> io::Result<()>
+where
+    F: FnOnce(&mut Cache),
+{
+    let lock = File::create(LOCK_PATH)?;
+    lock.lock()?;
+
+    let mut cache = cache().unwrap_or_default();
+    f(&mut cache);
+    let data = db::encode(&cache)?;
 
-        // Add this user back
-        new_users.push(user.clone());
+    file::write_atomically(CACHE_PATH, &data, 0o644)?;
 
-        // Continue
-        cache.user = new_users;
+    // Migration is complete once the new cache is written
+    if fs::exists(TOML_CACHE_PATH)? {
+        fs::remove_file(TOML_CACHE_PATH)?;
     }
-    let toml = toml::to_string_pretty(&cache).unwrap();
-    let _ = fs::write(CACHE_PATH, toml);
-    let _ = fs::set_permissions(CACHE_PATH, fs::Permissions::from_mode(0o644));
+    Ok(())
 }
@@ -0,0 +1,343 @@
+//! The indexed on-disk cache format. Users are stored as separate records
+//! with hash tables indexing them by UID and by name, so that a single user
+//! can be found in a memory-mapped file without reading the whole cache.
+//!
+//! All integers are little-endian. The file starts with a header:
+//!
+//! | Offset | Size | Content                              |
+//! |--------|------|--------------------------------------|
+//! | 0      | 8    | [`MAGIC`]                            |
+//! | 8      | 4    | schema version, [`VERSION`]          |
+//! | 12     | 4    | number of users                      |
+//! | 16     | 4    | number of hash buckets, a power of 2 |
+//! | 20     | 4    | reserved                             |
+//! | 24     | 16   | offset and length of the misses      |
+//! | 40     | 16   | offset and length of the rest        |
+//!
+//! It is followed by the UID table and the name table, each a list of
+//! buckets holding a 32-bit key (the UID, or the hash of the name) and the
+//! index of a user plus one (0 for an empty bucket). Collisions are resolved
+//! by linear probing. After these are the offsets of each user record, with
+//! one more offset for the end of the last record, then the records
+//! themselves as JSON. The misses are stored as a JSON list, and everything
+//! else in the cache as a JSON [`Cache`].
+
+use std::{fs::File, io};
+
+use memmap2::Mmap;
+
+use super::{Cache, Key, Miss, User};
+
+pub const MAGIC: &[u8; 8] = b"KCNSSDB\0";
+pub const VERSION: u32 = 2;
+
+const HEADER_LEN: usize = 56;
+const BUCKET_LEN: usize = 8;
+
+pub struct Db {
+    map: Mmap,
+    users: u32,
+    buckets: u32,
+}
+
+impl Db {
+    /// Open a cache file. Returns `None` if the file was written with a
+    /// different schema version.
+    pub fn open(path: &str) -> io::Result<Option<Db>> {
+        let file = File::open(path)?;
+        // SAFETY: the cache is only ever replaced by renaming a new file over
+        // it, never modified in place.
+        let map = unsafe { Mmap::map(&file)? };
+
+        if map.get(..MAGIC.len()) != Some(MAGIC) {
+            return Err(invalid("bad magic"));
+        }
+        let db = Db {
+            users: 0,
+            buckets: 0,
+            map,
+        };
+        if db.u32_at(8)? != VERSION {
+            return Ok(None);
+        }
+
+        let users = db.u32_at(12)?;
+        let buckets = db.u32_at(16)?;
+        if !buckets.is_power_of_two() {
+            return Err(invalid("bad bucket count"));
+        }
+        Ok(Some(Db {
+            users,
+            buckets,
+            ..db
+        }))
+    }
+
+    /// Find a single user.
+    pub fn user(&self, key: &Key) -> io::Result<Option<User>> {
+        let (table, hash) = match key {
+            Key::Uid(uid) => (0, *uid),
+            Key::Name(name) => (1, hash(name)),
+        };
+        let table = HEADER_LEN + table * self.buckets as usize * BUCKET_LEN;
+
+        let mask = self.buckets - 1;
+        let mut bucket = hash & mask;
+        for _ in 0..self.buckets {
+            let offset = table + bucket as usize * BUCKET_LEN;
+            let index = self.u32_at(offset + 4)?;
+            if index == 0 {
+                return Ok(None);
+            }
+            if self.u32_at(offset)? == hash {
+                let user = self.record(index - 1)?;
+                if key.matches(&user) {
+                    return Ok(Some(user));
+                }
+            }
+            bucket = (bucket + 1) & mask;
+        }
+        Ok(None)
+    }
+
+    pub fn misses(&self) -> io::Result<Vec<Miss>> {
+        Ok(serde_json::from_slice(self.section(24)?)?)
+    }
+
+    /// Read the whole cache.
+    pub fn to_cache(&self) -> io::Result<Cache> {
+        let mut cache: Cache = serde_json::from_slice(self.section(40)?)?;
+        cache.user = (0..self.users)
+            .map(|i| self.record(i))
+            .collect::<io::Result<_>>()?;
+        cache.missing = self.misses()?;
+        Ok(cache)
+    }
+
+    fn record(&self, index: u32) -> io::Result<User> {
+        let offsets = HEADER_LEN + 2 * self.buckets as usize * BUCKET_LEN;
+        let start = self.u64_at(offsets + index as usize * 8)?;
+        let end = self.u64_at(offsets + (index as usize + 1) * 8)?;
+        Ok(serde_json::from_slice(self.slice(start, end)?)?)
+    }
+
+    /// Get a section given the position of its offset and length in the header.
+    fn section(&self, header_offset: usize) -> io::Result<&[u8]> {
+        let start = self.u64_at(header_offset)?;
+        let len = self.u64_at(header_offset + 8)?;
+        self.slice(start, start.saturating_add(len))
+    }
+
+    fn slice(&self, start: u64, end: u64) -> io::Result<&[u8]> {
+        self.map
+            .get(start as usize..end as usize)
+            .ok_or_else(|| invalid("truncated"))
+    }
+
+    fn u32_at(&self, offset: usize) -> io::Result<u32> {
+        let bytes = self.slice(offset as u64, offset as u64 + 4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn u64_at(&self, offset: usize) -> io::Result<u64> {
+        let bytes = self.slice(offset as u64, offset as u64 + 8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
+
+/// Serialise a cache into the indexed format.
+pub fn encode(cache: &Cache) -> io::Result<Vec<u8>> {
+    let users = &cache.user;
+    let buckets = (users.len() * 2).max(1).next_power_of_two();
+    let mask = buckets as u32 - 1;
+
+    let mut uid_table = vec![(0u32, 0u32); buckets];
+    let mut name_table = vec![(0u32, 0u32); buckets];
+    let mut records = vec![];
+    let mut offsets = vec![];
+    let records_start = (HEADER_LEN + 2 * buckets * BUCKET_LEN + (users.len() + 1) * 8) as u64;
+
+    for (i, user) in users.iter().enumerate() {
+        let index = i as u32 + 1;
+        for (table, hash) in [
+            (&mut uid_table, user.uid),
+            (&mut name_table, hash(&user.username)),
+        ] {
+            let mut bucket = hash & mask;
+            while table[bucket as usize].1 != 0 {
+                bucket = (bucket + 1) & mask;
+            }
+            table[bucket as usize] = (hash, index);
+        }
+
+        offsets.push(records_start + records.len() as u64);
+        serde_json::to_writer(&mut records, user)?;
+    }
+    offsets.push(records_start + records.len() as u64);
+
+    let misses = serde_json::to_vec(&cache.missing)?;
+    let rest = serde_json::to_vec(&Cache {
+        user: vec![],
+        missing: vec![],
+        missing_groups: cache.missing_groups.clone(),
+        group: cache.group.clone(),
+        shadow: cache.shadow.clone(),
+        user_groups: cache.user_groups.clone(),
+    })?;
+    let misses_start = records_start + records.len() as u64;
+    let rest_start = misses_start + misses.len() as u64;
+
+    let mut out = Vec::with_capacity(rest_start as usize + rest.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&(users.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(buckets as u32).to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&misses_start.to_le_bytes());
+    out.extend_from_slice(&(misses.len() as u64).to_le_bytes());
+    out.extend_from_slice(&rest_start.to_le_bytes());
+    out.extend_from_slice(&(rest.len() as u64).to_le_bytes());
+    for (key, index) in uid_table.into_iter().chain(name_table) {
+        out.extend_from_slice(&key.to_le_bytes());
+        out.extend_from_slice(&index.to_le_bytes());
+    }
+    for offset in offsets {
+        out.extend_from_slice(&offset.to_le_bytes());
+    }
+    out.extend_from_slice(&records);
+    out.extend_from_slice(&misses);
+    out.extend_from_slice(&rest);
+    Ok(out)
+}
+
+/// 32-bit FNV-1a, which unlike the standard library's hasher is stable
+/// between builds.
+fn hash(name: &str) -> u32 {
+    name.bytes().fold(0x811c9dc5, |hash, b| {
+        (hash ^ b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid cache: {reason}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, io, process};
+
+    use super::{Db, VERSION, encode, hash};
+    use crate::cache::{Cache, Group, Key, Miss, User};
+
+    fn user(uid: libc::uid_t, username: &str) -> User {
+        User {
+            uid,
+            username: username.to_string(),
+            name: format!("User {uid}"),
+            gid: 1000,
+            home_dir: format!("/home/{username}"),
+            shell: "/bin/bash".to_string(),
+            cached_at: 0,
+            realm: "test".to_string(),
+        }
+    }
+
+    fn cache(users: Vec<User>) -> Cache {
+        Cache {
+            user: users,
+            group: vec![Group {
+                gid: 2000,
+                name: "staff".to_string(),
+                members: vec!["alice".to_string()],
+                cached_at: 0,
+                realm: "test".to_string(),
+            }],
+            missing: vec![Miss {
+                key: Key::Name("nobody".to_string()),
+                cached_at: 0,
+                realm: "test".to_string(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Open a cache file written with `data`.
+    fn open(test: &str, data: &[u8]) -> io::Result<Option<Db>> {
+        let path = env::temp_dir().join(format!("kcnssdb-{test}-{}", process::id()));
+        fs::write(&path, data)?;
+        let db = Db::open(path.to_str().unwrap());
+        fs::remove_file(&path)?;
+        db
+    }
+
+    #[test]
+    fn finds_users_by_uid_and_name() {
+        let users = (0..100)
+            .map(|i| user(10000 + i, &format!("user{i}")))
+            .collect::<Vec<_>>();
+        let db = open("lookup", &encode(&cache(users)).unwrap())
+            .unwrap()
+            .unwrap();
+
+        for i in 0..100 {
+            let by_uid = db.user(&Key::Uid(10000 + i)).unwrap().unwrap();
+            assert_eq!(by_uid.username, format!("user{i}"));
+            let by_name = db.user(&Key::Name(format!("user{i}"))).unwrap().unwrap();
+            assert_eq!(by_name.uid, 10000 + i);
+        }
+        assert!(db.user(&Key::Uid(9999)).unwrap().is_none());
+        assert!(
+            db.user(&Key::Name("user100".to_string()))
+                .unwrap()
+                .is_none()
+        );
+
+        let cache = db.to_cache().unwrap();
+        assert_eq!(cache.user.len(), 100);
+        assert_eq!(cache.group[0].members, ["alice"]);
+        assert_eq!(cache.missing[0].key, Key::Name("nobody".to_string()));
+        assert_eq!(db.misses().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn finds_users_with_colliding_name_hashes() {
+        assert_eq!(hash("user449599"), hash("user612382"));
+        let users = vec![user(1001, "user449599"), user(1002, "user612382")];
+        let db = open("collision", &encode(&cache(users)).unwrap())
+            .unwrap()
+            .unwrap();
+
+        let first = db.user(&Key::Name("user449599".to_string())).unwrap();
+        assert_eq!(first.unwrap().uid, 1001);
+        let second = db.user(&Key::Name("user612382".to_string())).unwrap();
+        assert_eq!(second.unwrap().uid, 1002);
+    }
+
+    #[test]
+    fn finds_nothing_in_empty_cache() {
+        let db = open("empty", &encode(&Cache::default()).unwrap())
+            .unwrap()
+            .unwrap();
+        assert!(db.user(&Key::Uid(1000)).unwrap().is_none());
+        assert!(db.to_cache().unwrap().user.is_empty());
+    }
+
+    #[test]
+    fn ignores_other_versions() {
+        let mut data = encode(&cache(vec![user(1000, "alice")])).unwrap();
+        data[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(open("version", &data).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let data = encode(&cache(vec![user(1000, "alice")])).unwrap();
+        for len in 0..data.len() {
+            let res = open("truncated", &data[..len]).and_then(|db| db.unwrap().to_cache());
+            assert!(res.is_err(), "truncated to {len} bytes");
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! The resident lookup daemon, which owns the connection to Keycloak so that
+//! processes using NSS do not need to contact it themselves.
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    mem,
+    os::{
+        fd::AsRawFd,
+        unix::{
+            fs::PermissionsExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use common::config::{self, Config, UidAssignment};
+
+use crate::{
+    log,
+    lookup::{self, Reply, Request},
+    openlog,
+};
+
+pub const SOCKET_PATH: &str = "/run/nss-keycloak.sock";
+
+/// How long either side waits for the other before giving up.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest request read, in bytes. Any local user can connect, so this and
+/// the number of connections are limited to keep them from exhausting the
+/// daemon.
+const MAX_REQUEST_LEN: u64 = 4096;
+
+/// Most connections served at once. Any more are closed unanswered, so that
+/// their clients fall back to the cache.
+const MAX_CONNECTIONS: usize = 64;
+
+/// Ask the daemon to answer a request. Each request is a single line of JSON,
+/// answered with a single line of JSON.
+pub(crate) fn request(request: &Request) -> io::Result<Reply> {
+    let mut stream = UnixStream::connect(SOCKET_PATH)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    serde_json::to_writer(&mut stream, request)?;
+    stream.write_all(b"\n")?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Run the daemon, answering requests on [`SOCKET_PATH`] until killed.
+pub fn run() -> Result<(), Box<dyn std::error::Error>> {
+    openlog();
+    config::create_if_not_exists()?;
+    let config = Arc::new(config::load()?);
+
+    if fs::exists(SOCKET_PATH)? {
+        fs::remove_file(SOCKET_PATH)?;
+    }
+    let listener = UnixListener::bind(SOCKET_PATH)?;
+    fs::set_permissions(SOCKET_PATH, fs::Permissions::from_mode(0o666))?;
+    log(libc::LOG_INFO, format!("Listening on {SOCKET_PATH}"));
+
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    log(libc::LOG_WARNING, "Too many connections, closing one");
+                    continue;
+                }
+                let config = config.clone();
+                let connections = connections.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(&config, stream) {
+                        log(libc::LOG_WARNING, format!("Failed to serve request: {e}"));
+                    }
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Err(e) => log(libc::LOG_ERR, format!("Failed to accept connection: {e}")),
+        }
+    }
+
+    Ok(())
+}
+
+fn serve(config: &Config, mut stream: UnixStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut line = String::new();
+    BufReader::new((&stream).take(MAX_REQUEST_LEN)).read_line(&mut line)?;
+    let request: Request = serde_json::from_str(&line)?;
+    log(libc::LOG_DEBUG, format!("Request: {request:?}"));
+
+    let reply = match peer_uid(&stream)? {
+        0 => lookup::keycloak(config, request),
+        _ if request.is_privileged() => Reply::Unavail,
+        // Assigning a UID writes to Keycloak and searches the whole realm, so
+        // other users may only look up users who already have one
+        _ if config.uid_assignment == UidAssignment::Lookup => lookup::keycloak(
+            &Config {
+                uid_assignment: UidAssignment::Login,
+                ..config.clone()
+            },
+            request,
+        ),
+        _ => lookup::keycloak(config, request),
+    };
+
+    serde_json::to_writer(&mut stream, &reply)?;
+    stream.write_all(b"\n")
+}
+
+/// Get the UID of the process on the other end of a socket.
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&raw mut cred).cast(),
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
@@ -0,0 +1,494 @@
+use std::collections::{HashMap, HashSet};
+
+use common::{
+    api::{self, types::GroupRepresentation},
+    config::Config,
+    validate::{Validator, is_valid_group},
+    visibility,
+};
+use libnss::{
+    group::{Group, GroupHooks},
+    initgroups::InitgroupsHooks,
+    interop::Response,
+    libnss_group_hooks, libnss_initgroups_hooks,
+};
+
+use crate::{
+    cache, log,
+    lookup::{Reply, Request, lookup},
+    to_group::ToGroup,
+    validate,
+};
+
+struct KeycloakGroup;
+libnss_group_hooks!(keycloak, KeycloakGroup);
+
+impl GroupHooks for KeycloakGroup {
+    fn get_all_entries() -> Response<Vec<Group>> {
+        match lookup("group get_all_entries", Request::AllGroups) {
+            Reply::Groups(groups) => {
+                Response::Success(groups.into_iter().map(Into::into).collect())
+            }
+            reply => reply.into_response(),
+        }
+    }
+
+    fn get_entry_by_gid(gid: libc::gid_t) -> Response<Group> {
+        match lookup("group get_entry_by_gid", Request::GroupByGid(gid)) {
+            Reply::Groups(groups) => Reply::first(groups),
+            reply => reply.into_response(),
+        }
+    }
+
+    fn get_entry_by_name(name: String) -> Response<Group> {
+        match lookup("group get_entry_by_name", Request::GroupByName(name)) {
+            Reply::Groups(groups) => Reply::first(groups),
+            reply => reply.into_response(),
+        }
+    }
+}
+
+struct KeycloakInitgroups;
+libnss_initgroups_hooks!(keycloak, KeycloakInitgroups);
+
+impl InitgroupsHooks for KeycloakInitgroups {
+    fn get_entries_by_user(user: String) -> Response<Vec<Group>> {
+        match lookup("get_entries_by_user", Request::UserGroups(user)) {
+            Reply::Gids(gids) => Response::Success(gids.into_iter().map(gid_only).collect()),
+            reply => reply.into_response(),
+        }
+    }
+}
+
+pub(crate) fn all_groups(config: &Config) -> Response<Vec<Group>> {
+    let groups = match fetch_groups(config) {
+        Ok(groups) => groups,
+        Err(e) => {
+            log(libc::LOG_ERR, format!("Failed to get groups: {e}"));
+            return Response::TryAgain;
+        }
+    };
+
+    // Members are checked for shared UIDs against every user at once
+    let mut validator = Validator::new(config, log);
+    if let Err(e) = validator.find_all_duplicates() {
+        log(libc::LOG_ERR, format!("Failed to get users: {e}"));
+        return Response::TryAgain;
+    }
+
+    let mut entries = vec![];
+    let mut allowed = HashMap::new();
+    for (group, gid) in unique_groups(config, groups) {
+        match to_group(config, &group, gid, &mut validator, &mut allowed) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                log(libc::LOG_ERR, format!("Failed to get group members: {e}"));
+                return Response::TryAgain;
+            }
+        }
+    }
+
+    cache::replace_groups(
+        &config.realm,
+        &entries.iter().map(Into::into).collect::<Vec<_>>(),
+    );
+    Response::Success(entries)
+}
+
+pub(crate) fn group_by_gid(config: &Config, gid: libc::gid_t) -> Response<Group> {
+    find_group(config, cache::GroupKey::Gid(gid))
+}
+
+pub(crate) fn group_by_name(config: &Config, name: String) -> Response<Group> {
+    find_group(config, cache::GroupKey::Name(name))
+}
+
+pub(crate) fn user_groups(config: &Config, user: String) -> Response<Vec<libc::gid_t>> {
+    let Some(username) = config.keycloak_name(&user) else {
+        return Response::NotFound;
+    };
+    let mut query = HashMap::new();
+    query.insert("exact", "true");
+    query.insert("username", username);
+
+    let res = api::get_users(config, query, |v| log(libc::LOG_DEBUG, v));
+    let users = match res {
+        Ok(users) => users,
+        Err(e) => {
+            log(libc::LOG_ERR, format!("Failed to get user: {e}"));
+            return Response::TryAgain;
+        }
+    };
+    let [user] = users.as_slice() else {
+        return Response::NotFound;
+    };
+    let mut validator = Validator::new(config, log);
+    if let Err(e) = validator.find_duplicates_of(user) {
+        log(libc::LOG_ERR, format!("Failed to get user: {e}"));
+        return Response::TryAgain;
+    }
+    if validator.uid(user).is_none() {
+        return Response::NotFound;
+    }
+    if let Err(response) = validate::check_allowed(config, user) {
+        return response;
+    }
+
+    let gids = match get_supplementary_gids(config, &user.id) {
+        Ok(gids) => gids,
+        Err(e) => {
+            log(libc::LOG_ERR, format!("Failed to get user's groups: {e}"));
+            return Response::TryAgain;
+        }
+    };
+    log(
+        libc::LOG_DEBUG,
+        format!("Supplementary groups of {}: {gids:?}", user.username),
+    );
+
+    cache::update_user_groups_cache(&config.unix_name(&user.username), &gids);
+    Response::Success(gids)
+}
+
+/// Get the GIDs of the Keycloak groups a user is a member of, and of any
+/// groups granted to them through [`Config::role_groups`].
+fn get_supplementary_gids(
+    config: &Config,
+    user_id: &str,
+) -> Result<Vec<libc::gid_t>, Box<dyn std::error::Error>> {
+    let groups = api::user_groups(config, user_id, |v| log(libc::LOG_DEBUG, v))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut gids = visible_gids(config, groups)?;
+
+    if !config.role_groups.is_empty() {
+        let client_ids = config
+            .role_groups
+            .keys()
+            .filter_map(|role| role.split_once('/').map(|(client_id, _)| client_id))
+            .collect::<Vec<_>>();
+        let roles =
+            visibility::get_user_roles(config, user_id, &client_ids, |v| log(libc::LOG_DEBUG, v))?;
+
+        gids.extend(
+            roles
+                .iter()
+                .filter_map(|role| config.role_groups.get(role).copied()),
+        );
+    }
+
+    gids.sort_unstable();
+    gids.dedup();
+    Ok(gids)
+}
+
+/// Get the GIDs of those of `groups` that a lookup would show, checking each
+/// against the groups sharing its name or GID.
+fn visible_gids(
+    config: &Config,
+    groups: Vec<GroupRepresentation>,
+) -> Result<Vec<libc::gid_t>, Box<dyn std::error::Error>> {
+    let ids = groups
+        .iter()
+        .map(|group| group.id.clone())
+        .collect::<HashSet<_>>();
+    let mut candidates = if config.id_mapping.is_some() {
+        // Mapped GIDs can't be searched for, so look through every group
+        fetch_groups(config)?
+    } else {
+        let mut candidates = vec![];
+        for group in &groups {
+            candidates.extend(groups_named(config, &group.name)?);
+            if let Some(gid) = get_gid(config, group) {
+                candidates.extend(groups_with_gid(config, gid)?);
+            }
+        }
+        candidates
+    };
+    candidates.extend(groups);
+    candidates.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+    candidates.dedup_by(|a, b| a.id == b.id);
+
+    Ok(unique_groups(config, candidates)
+        .into_iter()
+        .filter(|(group, _)| ids.contains(&group.id))
+        .map(|(_, gid)| gid)
+        .collect())
+}
+
+/// A group carrying only a GID, which is all that initgroups needs.
+fn gid_only(gid: libc::gid_t) -> Group {
+    Group {
+        name: String::new(),
+        passwd: "x".to_string(),
+        gid,
+        members: vec![],
+    }
+}
+
+/// Find a single group from Keycloak that has a GID, searching for it by name
+/// or GID rather than fetching every group.
+fn find_group(config: &Config, key: cache::GroupKey) -> Response<Group> {
+    if let Some(response) = from_fresh_cache(config, &key) {
+        return response;
+    }
+
+    // Groups sharing its name or GID are also needed, to hide them all
+    let res = match (&key, &config.id_mapping) {
+        (cache::GroupKey::Name(name), mapping) => {
+            groups_named(config, name).and_then(|mut groups| {
+                if mapping.is_none() {
+                    let gids = groups
+                        .iter()
+                        .filter(|group| group.name == *name)
+                        .filter_map(|group| get_gid(config, group))
+                        .collect::<Vec<_>>();
+                    for gid in gids {
+                        groups.extend(groups_with_gid(config, gid)?);
+                    }
+                }
+                Ok(groups)
+            })
+        }
+        (cache::GroupKey::Gid(gid), None) => {
+            groups_with_gid(config, *gid).and_then(|mut groups| {
+                let names = groups
+                    .iter()
+                    .filter(|group| get_gid(config, group) == Some(*gid))
+                    .map(|group| group.name.clone())
+                    .collect::<Vec<_>>();
+                for name in names {
+                    groups.extend(groups_named(config, &name)?);
+                }
+                Ok(groups)
+            })
+        }
+        // Mapped GIDs can't be searched for, so look through every group
+        (cache::GroupKey::Gid(_), Some(_)) => fetch_groups(config),
+    };
+    let mut groups = match res {
+        Ok(groups) => groups,
+        Err(e) => {
+            log(libc::LOG_ERR, format!("Failed to get groups: {e}"));
+            return Response::TryAgain;
+        }
+    };
+    groups.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+    groups.dedup_by(|a, b| a.id == b.id);
+
+    let Some((group, gid)) = unique_groups(config, groups)
+        .into_iter()
+        .find(|(group, gid)| match &key {
+            cache::GroupKey::Gid(key) => gid == key,
+            cache::GroupKey::Name(name) => group.name == *name,
+        })
+    else {
+        cache::record_missing_group(&config.realm, key, config.negative_cache_ttl);
+        return Response::NotFound;
+    };
+    log(libc::LOG_DEBUG, format!("{group:?}"));
+
+    match to_group(
+        config,
+        &group,
+        gid,
+        &mut Validator::new(config, log),
+        &mut HashMap::new(),
+    ) {
+        Ok(entry) => {
+            cache::update_group_cache(&config.realm, &[(&entry).into()]);
+            Response::Success(entry)
+        }
+        Err(e) => {
+            log(libc::LOG_ERR, format!("Failed to get group members: {e}"));
+            Response::TryAgain
+        }
+    }
+}
+
+/// Answer a lookup from the cache if the group was fetched or found missing
+/// recently enough.
+fn from_fresh_cache(config: &Config, key: &cache::GroupKey) -> Option<Response<Group>> {
+    let cache = cache::cache()?;
+    if cache.missing_groups.iter().any(|m| {
+        m.realm == config.realm
+            && m.key == *key
+            && cache::is_fresh(m.cached_at, config.negative_cache_ttl)
+    }) {
+        log(libc::LOG_DEBUG, format!("Known missing: {key:?}"));
+        return Some(Response::NotFound);
+    }
+    let group = cache.group.into_iter().find(|g| {
+        g.realm == config.realm && key.matches(g) && cache::is_fresh(g.cached_at, config.cache_ttl)
+    })?;
+    log(libc::LOG_DEBUG, format!("Answering from cache: {group:?}"));
+    Some(Response::Success(group.into()))
+}
+
+/// Search for the groups with a name, including any other groups returned
+/// with them.
+fn groups_named(
+    config: &Config,
+    name: &str,
+) -> Result<Vec<GroupRepresentation>, Box<dyn std::error::Error>> {
+    let mut query = HashMap::new();
+    query.insert("search", name.to_string());
+    query.insert("exact", "true".to_string());
+    search_groups(config, query)
+}
+
+/// Search for the groups with a GID attribute, including any other groups
+/// returned with them.
+fn groups_with_gid(
+    config: &Config,
+    gid: libc::gid_t,
+) -> Result<Vec<GroupRepresentation>, Box<dyn std::error::Error>> {
+    let mut query = HashMap::new();
+    query.insert("q", format!("{}:{gid}", config.gid_attribute_id));
+    search_groups(config, query)
+}
+
+/// Search for groups, with the subgroups they are returned with flattened
+/// into the list.
+fn search_groups(
+    config: &Config,
+    query: HashMap<&'static str, String>,
+) -> Result<Vec<GroupRepresentation>, Box<dyn std::error::Error>> {
+    let mut pending = api::search_groups(config, query, |v| log(libc::LOG_DEBUG, v))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut groups = vec![];
+    while let Some(mut group) = pending.pop() {
+        pending.extend(std::mem::take(&mut group.sub_groups));
+        groups.push(group);
+    }
+    Ok(groups)
+}
+
+/// Get the groups that may be shown with their GIDs, hiding any that share a
+/// name or GID with another, as which one is meant can't be known.
+fn unique_groups(
+    config: &Config,
+    groups: Vec<GroupRepresentation>,
+) -> Vec<(GroupRepresentation, libc::gid_t)> {
+    let groups = groups
+        .into_iter()
+        .filter(|group| is_valid_group(group, log))
+        .filter_map(|group| get_gid(config, &group).map(|gid| (group, gid)))
+        .collect::<Vec<_>>();
+
+    let mut names = HashMap::<_, Vec<_>>::new();
+    let mut gids = HashMap::<_, Vec<_>>::new();
+    for (group, gid) in &groups {
+        names
+            .entry(group.name.clone())
+            .or_default()
+            .push(group.path.clone());
+        gids.entry(*gid).or_default().push(group.path.clone());
+    }
+    for (name, paths) in names.iter().filter(|(_, paths)| paths.len() > 1) {
+        log(
+            libc::LOG_ERR,
+            format!(
+                "Group name {name} is shared by Keycloak groups {}",
+                paths.join(", ")
+            ),
+        );
+    }
+    for (gid, paths) in gids.iter().filter(|(_, paths)| paths.len() > 1) {
+        log(
+            libc::LOG_ERR,
+            format!(
+                "GID {gid} is shared by Keycloak groups {}",
+                paths.join(", ")
+            ),
+        );
+    }
+
+    groups
+        .into_iter()
+        .filter(|(group, gid)| names[&group.name].len() == 1 && gids[gid].len() == 1)
+        .collect()
+}
+
+/// Get every group in the realm, with subgroups flattened into the list.
+fn fetch_groups(config: &Config) -> Result<Vec<GroupRepresentation>, Box<dyn std::error::Error>> {
+    let mut pending =
+        api::groups(config, |v| log(libc::LOG_DEBUG, v)).collect::<Result<Vec<_>, _>>()?;
+    let mut groups = vec![];
+    while let Some(mut group) = pending.pop() {
+        let mut children = std::mem::take(&mut group.sub_groups);
+        if children.is_empty() && group.sub_group_count.is_some_and(|c| c > 0) {
+            children = api::group_children(config, &group.id, |v| log(libc::LOG_DEBUG, v))
+                .collect::<Result<_, _>>()?;
+        }
+        pending.extend(children);
+        groups.push(group);
+    }
+    Ok(groups)
+}
+
+/// Get the GID of a group, if it has a valid one.
+fn get_gid(config: &Config, group: &GroupRepresentation) -> Option<libc::gid_t> {
+    if let Some(mapping) = &config.id_mapping {
+        return Some(mapping.map_id(&group.id));
+    }
+
+    let gid = group.attributes.get(&config.gid_attribute_id)?.first()?;
+    match gid.parse::<libc::gid_t>() {
+        Ok(gid) if config.allows_gid(gid) => Some(gid),
+        Ok(gid) => {
+            log(
+                libc::LOG_WARNING,
+                format!(
+                    "Ignoring group {} with GID {gid} outside {}-{}",
+                    group.path, config.min_gid, config.max_gid
+                ),
+            );
+            None
+        }
+        Err(e) => {
+            log(
+                libc::LOG_WARNING,
+                format!(
+                    "Ignoring group {} with invalid GID {gid:?}: {e}",
+                    group.path
+                ),
+            );
+            None
+        }
+    }
+}
+
+/// Build a group entry from its visible members, checking each the same way
+/// as a user lookup, including roles held through groups and composites.
+/// `validator` and `allowed` keep what they found for each user, so that
+/// users in several groups are only checked once.
+fn to_group(
+    config: &Config,
+    group: &GroupRepresentation,
+    gid: libc::gid_t,
+    validator: &mut Validator<'_, impl Fn(libc::c_int, String)>,
+    allowed: &mut HashMap<String, bool>,
+) -> Result<Group, Box<dyn std::error::Error>> {
+    let mut members = vec![];
+    for member in api::group_members(config, &group.id, |v| log(libc::LOG_DEBUG, v)) {
+        let member = member?;
+        validator.find_duplicates_of(&member)?;
+        if validator.uid(&member).is_none() {
+            continue;
+        }
+        let is_allowed = match allowed.get(&member.id) {
+            Some(&is_allowed) => is_allowed,
+            None => {
+                let is_allowed =
+                    visibility::is_allowed(config, &member, |v| log(libc::LOG_DEBUG, v))?;
+                allowed.insert(member.id.clone(), is_allowed);
+                is_allowed
+            }
+        };
+        if is_allowed {
+            members.push(config.unix_name(&member.username));
+        }
+    }
+
+    Ok(group.to_group(gid, members))
+}
@@ -0,0 +1,278 @@
+use common::{
+    config::{self, Config},
+    discovery,
+};
+use libnss::interop::Response;
+use serde::{Deserialize, Serialize};
+
+use crate::{cache, daemon, group, log, openlog, passwd, shadow};
+
+/// A question asked of the NSS module, answered directly from Keycloak, by the
+/// daemon or from the cache.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Request {
+    AllUsers,
+    UserByUid(libc::uid_t),
+    UserByName(String),
+    AllGroups,
+    GroupByGid(libc::gid_t),
+    GroupByName(String),
+    UserGroups(String),
+    AllShadows,
+    ShadowByName(String),
+}
+
+impl Request {
+    /// Whether only root may ask this.
+    pub fn is_privileged(&self) -> bool {
+        matches!(self, Request::AllShadows | Request::ShadowByName(_))
+    }
+
+    /// Whether this asks for every entry rather than a single one.
+    pub fn is_listing(&self) -> bool {
+        matches!(
+            self,
+            Request::AllUsers | Request::AllGroups | Request::AllShadows
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Reply {
+    Users(Vec<cache::User>),
+    Groups(Vec<cache::Group>),
+    Gids(Vec<libc::gid_t>),
+    Shadows(Vec<cache::Shadow>),
+    NotFound,
+    TryAgain,
+    Unavail,
+}
+
+impl Reply {
+    /// Convert a reply without entries, or of the wrong kind, to a response.
+    pub fn into_response<T>(self) -> Response<T> {
+        match self {
+            Reply::NotFound => Response::NotFound,
+            Reply::TryAgain => Response::TryAgain,
+            Reply::Unavail => Response::Unavail,
+            reply => {
+                log(libc::LOG_ERR, format!("Unexpected reply: {reply:?}"));
+                Response::Unavail
+            }
+        }
+    }
+
+    /// Respond with the first of some entries.
+    pub fn first<T, R>(entries: Vec<T>) -> Response<R>
+    where
+        T: Into<R>,
+    {
+        entries
+            .into_iter()
+            .next()
+            .map_or(Response::NotFound, |e| Response::Success(e.into()))
+    }
+
+    fn from_response<T, F>(response: Response<T>, f: F) -> Self
+    where
+        F: FnOnce(T) -> Reply,
+    {
+        match response {
+            Response::Success(entries) => f(entries),
+            Response::NotFound => Reply::NotFound,
+            Response::TryAgain => Reply::TryAgain,
+            Response::Unavail | Response::Return => Reply::Unavail,
+        }
+    }
+}
+
+/// Set up logging for an NSS function and answer a request for it, either
+/// directly from Keycloak, through the daemon, or from the cache.
+pub fn lookup(function: &str, request: Request) -> Reply {
+    openlog();
+    log(
+        libc::LOG_DEBUG,
+        format!("{function}, v{}", env!("CARGO_PKG_VERSION")),
+    );
+
+    if config::create_if_not_exists().is_err() {
+        log(
+            libc::LOG_WARNING,
+            format!(
+                "Default config created, update it at {}",
+                config::CONFIG_PATH
+            ),
+        );
+        return Reply::TryAgain;
+    }
+
+    match config::read() {
+        Ok(mut config) if !config.daemon => match discovery::discover(&mut config) {
+            Ok(()) => return keycloak(&config, request),
+            Err(e) => {
+                log(
+                    libc::LOG_ERR,
+                    format!("Failed to discover Keycloak endpoints ({e}), trying cache!"),
+                );
+                return from_cache(request);
+            }
+        },
+        Ok(_) => (),
+        Err(_) => log(
+            libc::LOG_WARNING,
+            "Failed to read config (might be running as a user, in which case this is normal), trying daemon!",
+        ),
+    }
+
+    match daemon::request(&request) {
+        Ok(reply) => reply,
+        Err(e) => {
+            log(
+                libc::LOG_WARNING,
+                format!("Failed to contact daemon ({e}), trying cache!"),
+            );
+            from_cache(request)
+        }
+    }
+}
+
+/// Answer a request from Keycloak, searching each realm in order. Single
+/// entries come from the first realm that has them, and lists from every
+/// realm.
+pub fn keycloak(config: &Config, request: Request) -> Reply {
+    let mut listed = None;
+    for config in config.realm_configs() {
+        let reply = realm(&config, request.clone());
+        if !request.is_listing() {
+            match reply {
+                Reply::NotFound => continue,
+                reply => return reply,
+            }
+        }
+        listed = match (listed, reply) {
+            (listed, Reply::NotFound) => listed,
+            (Some(Reply::Users(mut all)), Reply::Users(users)) => {
+                all.extend(users);
+                Some(Reply::Users(all))
+            }
+            (Some(Reply::Groups(mut all)), Reply::Groups(groups)) => {
+                all.extend(groups);
+                Some(Reply::Groups(all))
+            }
+            (Some(Reply::Shadows(mut all)), Reply::Shadows(shadows)) => {
+                all.extend(shadows);
+                Some(Reply::Shadows(all))
+            }
+            (None, reply @ (Reply::Users(_) | Reply::Groups(_) | Reply::Shadows(_))) => Some(reply),
+            (_, reply) => return reply,
+        };
+    }
+    listed.unwrap_or(Reply::NotFound)
+}
+
+/// Answer a request from a single realm.
+fn realm(config: &Config, request: Request) -> Reply {
+    match request {
+        Request::AllUsers => Reply::from_response(passwd::all_users(config), |passwds| {
+            Reply::Users(passwds.iter().map(Into::into).collect())
+        }),
+        Request::UserByUid(uid) => Reply::from_response(passwd::user_by_uid(config, uid), |p| {
+            Reply::Users(vec![(&p).into()])
+        }),
+        Request::UserByName(name) => {
+            Reply::from_response(passwd::user_by_name(config, name), |p| {
+                Reply::Users(vec![(&p).into()])
+            })
+        }
+        Request::AllGroups => Reply::from_response(group::all_groups(config), |groups| {
+            Reply::Groups(groups.iter().map(Into::into).collect())
+        }),
+        Request::GroupByGid(gid) => Reply::from_response(group::group_by_gid(config, gid), |g| {
+            Reply::Groups(vec![(&g).into()])
+        }),
+        Request::GroupByName(name) => {
+            Reply::from_response(group::group_by_name(config, name), |g| {
+                Reply::Groups(vec![(&g).into()])
+            })
+        }
+        Request::UserGroups(name) => {
+            Reply::from_response(group::user_groups(config, name), Reply::Gids)
+        }
+        Request::AllShadows => Reply::from_response(shadow::all_shadows(config), |shadows| {
+            Reply::Shadows(shadows.iter().map(Into::into).collect())
+        }),
+        Request::ShadowByName(name) => {
+            Reply::from_response(shadow::shadow_by_name(config, name), |s| {
+                Reply::Shadows(vec![(&s).into()])
+            })
+        }
+    }
+}
+
+/// Answer a request from the cache. As the cache may be incomplete, a miss
+/// means try again rather than not found.
+fn from_cache(request: Request) -> Reply {
+    fn hit<T>(entries: Vec<T>, reply: fn(Vec<T>) -> Reply) -> Reply {
+        if entries.is_empty() {
+            Reply::TryAgain
+        } else {
+            reply(entries)
+        }
+    }
+
+    fn whole<F>(f: F) -> Reply
+    where
+        F: FnOnce(cache::Cache) -> Reply,
+    {
+        cache::cache().map_or(Reply::TryAgain, f)
+    }
+
+    // The cache is readable by all, but shadow entries are only for root
+    // SAFETY: geteuid has no preconditions
+    if request.is_privileged() && unsafe { libc::geteuid() } != 0 {
+        return Reply::Unavail;
+    }
+
+    match request {
+        Request::AllUsers => whole(|cache| Reply::Users(cache.user)),
+        // Single users can be found without reading the whole cache
+        Request::UserByUid(uid) => hit(
+            cache::user(&cache::Key::Uid(uid)).into_iter().collect(),
+            Reply::Users,
+        ),
+        Request::UserByName(name) => hit(
+            cache::user(&cache::Key::Name(name)).into_iter().collect(),
+            Reply::Users,
+        ),
+        Request::AllGroups => whole(|cache| Reply::Groups(cache.group)),
+        Request::GroupByGid(gid) => whole(|cache| {
+            hit(
+                cache.group.into_iter().filter(|g| g.gid == gid).collect(),
+                Reply::Groups,
+            )
+        }),
+        Request::GroupByName(name) => whole(|cache| {
+            hit(
+                cache.group.into_iter().filter(|g| g.name == name).collect(),
+                Reply::Groups,
+            )
+        }),
+        Request::UserGroups(name) => whole(|cache| {
+            cache
+                .user_groups
+                .get(&name)
+                .map_or(Reply::TryAgain, |gids| Reply::Gids(gids.clone()))
+        }),
+        Request::AllShadows => whole(|cache| Reply::Shadows(cache.shadow)),
+        Request::ShadowByName(name) => whole(|cache| {
+            hit(
+                cache
+                    .shadow
+                    .into_iter()
+                    .filter(|s| s.name == name)
+                    .collect(),
+                Reply::Shadows,
+            )
+        }),
+    }
+}
@@ -0,0 +1,194 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use common::{
+    api::get_users,
+    config::{Config, UidAssignment},
+    uid,
+    validate::Validator,
+    visibility,
+};
+use libnss::{
+    interop::Response,
+    libnss_passwd_hooks,
+    passwd::{Passwd, PasswdHooks},
+};
+
+use crate::{
+    cache, log,
+    lookup::{Reply, Request, lookup},
+    to_passwd::ToPasswd,
+    validate,
+};
+
+struct KeycloakPasswd;
+libnss_passwd_hooks!(keycloak, KeycloakPasswd);
+
+impl PasswdHooks for KeycloakPasswd {
+    fn get_all_entries() -> Response<Vec<Passwd>> {
+        match lookup("get_all_entries", Request::AllUsers) {
+            Reply::Users(users) => Response::Success(users.into_iter().map(Into::into).collect()),
+            reply => reply.into_response(),
+        }
+    }
+
+    fn get_entry_by_uid(uid: libc::uid_t) -> Response<Passwd> {
+        match lookup("get_entry_by_uid", Request::UserByUid(uid)) {
+            Reply::Users(users) => Reply::first(users),
+            reply => reply.into_response(),
+        }
+    }
+
+    fn get_entry_by_name(name: String) -> Response<Passwd> {
+        match lookup("get_entry_by_name", Request::UserByName(name)) {
+            Reply::Users(users) => Reply::first(users),
+            reply => reply.into_response(),
+        }
+    }
+}
+
+pub(crate) fn all_users(config: &Config) -> Response<Vec<Passwd>> {
+    let res = visibility::users(config, |v| log(libc::LOG_DEBUG, v));
+    if let Err(e) = res {
+        log(libc::LOG_ERR, format!("Failed to get user: {e}"));
+        return Response::TryAgain;
+    }
+    let res = res.unwrap();
+    let validator = Validator::with_users(config, &res, log);
+    let passwds = res
+        .iter()
+        .filter_map(|ur| validator.uid(ur).map(|uid| ur.to_passwd(config, uid)))
+        .collect::<Vec<_>>();
+
+    cache::replace_users(
+        &config.realm,
+        &passwds.iter().map(Into::into).collect::<Vec<_>>(),
+    );
+    // SAFETY: just validated
+    Response::Success(passwds)
+}
+
+pub(crate) fn user_by_uid(config: &Config, uid: libc::uid_t) -> Response<Passwd> {
+    let key = cache::Key::Uid(uid);
+    if let Some(response) = from_fresh_cache(config, &key) {
+        return response;
+    }
+
+    let res = uid::users_with_uid(config, uid, |v| log(libc::LOG_DEBUG, v));
+    if let Err(e) = res {
+        log(libc::LOG_ERR, format!("Failed to get user: {e}"));
+        return Response::TryAgain;
+    }
+    let res = res.unwrap();
+    let validator = Validator::with_users(config, &res, log);
+    // Of users sharing a UID, at most the one that keeps it is valid
+    let Some(user) = res.iter().find(|user| validator.uid(user) == Some(uid)) else {
+        cache::record_missing(&config.realm, key, config.negative_cache_ttl);
+        return Response::NotFound;
+    };
+    log(libc::LOG_DEBUG, format!("{user:?}"));
+    if let Err(response) = validate::check_allowed(config, user) {
+        return response;
+    }
+
+    let passwd = user.to_passwd(config, uid);
+    cache::update_cache(&config.realm, &[(&passwd).into()]);
+    Response::Success(passwd)
+}
+
+pub(crate) fn user_by_name(config: &Config, name: String) -> Response<Passwd> {
+    let Some(username) = config.keycloak_name(&name) else {
+        return Response::NotFound;
+    };
+    let mut query = HashMap::new();
+    query.insert("exact", Cow::Borrowed("true"));
+    query.insert("username", Cow::Owned(username.to_string()));
+
+    let key = cache::Key::Name(name);
+    if let Some(response) = from_fresh_cache(config, &key) {
+        return response;
+    }
+
+    let res = get_users(config, query, |v| log(libc::LOG_DEBUG, v));
+    if let Err(e) = res {
+        log(libc::LOG_ERR, format!("Failed to get user: {e}"));
+        return Response::TryAgain;
+    }
+    let res = res.unwrap();
+
+    if res.len() != 1 {
+        cache::record_missing(&config.realm, key, config.negative_cache_ttl);
+        return Response::NotFound;
+    }
+
+    // SAFETY: checked above
+    let user = res.first().unwrap();
+    log(libc::LOG_DEBUG, format!("{user:?}"));
+    // Checked before any UID is assigned to the user
+    let mut validator = Validator::new(config, log);
+    if !validator.is_valid_name(user) {
+        cache::record_missing(&config.realm, key, config.negative_cache_ttl);
+        return Response::NotFound;
+    }
+    if let Err(response) = validate::check_allowed(config, user) {
+        return response;
+    }
+
+    let uid = if let Some(uid) = uid::uid_of(config, user) {
+        log(libc::LOG_DEBUG, format!("User UID known: {uid}"));
+        if let Err(e) = validator.find_duplicates_of(user) {
+            log(libc::LOG_ERR, format!("Failed to get user: {e}"));
+            return Response::TryAgain;
+        }
+        uid
+    } else if user.attributes.contains_key(&config.uid_attribute_id) {
+        log(
+            libc::LOG_ERR,
+            format!("User {} has an invalid UID attribute", user.username),
+        );
+        return Response::NotFound;
+    } else if config.uid_assignment != UidAssignment::Lookup {
+        // Not recorded as missing, as a UID may be assigned at any moment by
+        // logging in
+        log(
+            libc::LOG_DEBUG,
+            format!("User {} has no UID yet", user.username),
+        );
+        return Response::NotFound;
+    } else {
+        match uid::assign_uid(config, user, log) {
+            Ok(uid) => uid,
+            Err(e) => {
+                log(libc::LOG_ERR, format!("Failed to assign UID: {e}"));
+                return Response::TryAgain;
+            }
+        }
+    };
+    if !validator.is_valid(user, uid) {
+        cache::record_missing(&config.realm, key, config.negative_cache_ttl);
+        return Response::NotFound;
+    }
+
+    let passwd = user.to_passwd(config, uid);
+    cache::update_cache(&config.realm, &[(&passwd).into()]);
+    Response::Success(passwd)
+}
+
+/// Answer a lookup from the cache if the user was fetched or found missing
+/// recently enough.
+fn from_fresh_cache(config: &Config, key: &cache::Key) -> Option<Response<Passwd>> {
+    if let Some(user) = cache::user(key)
+        .filter(|u| u.realm == config.realm && cache::is_fresh(u.cached_at, config.cache_ttl))
+    {
+        log(libc::LOG_DEBUG, format!("Answering from cache: {user:?}"));
+        return Some(Response::Success(user.into()));
+    }
+    if cache::misses().iter().any(|m| {
+        m.realm == config.realm
+            && m.key == *key
+            && cache::is_fresh(m.cached_at, config.negative_cache_ttl)
+    }) {
+        log(libc::LOG_DEBUG, format!("Known missing: {key:?}"));
+        return Some(Response::NotFound);
+    }
+    None
+}
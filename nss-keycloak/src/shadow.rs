@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+
+use common::{
+    api::{self, types::UserRepresentation},
+    config::Config,
+    validate::Validator,
+    visibility,
+};
+use libnss::{
+    interop::Response,
+    libnss_shadow_hooks,
+    shadow::{Shadow, ShadowHooks},
+};
+
+use crate::{
+    cache, log,
+    lookup::{Reply, Request, lookup},
+    to_shadow::ToShadow,
+    validate,
+};
+
+const MILLIS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+struct KeycloakShadow;
+libnss_shadow_hooks!(keycloak, KeycloakShadow);
+
+impl ShadowHooks for KeycloakShadow {
+    fn get_all_entries() -> Response<Vec<Shadow>> {
+        match lookup("shadow get_all_entries", Request::AllShadows) {
+            Reply::Shadows(shadows) => {
+                Response::Success(shadows.into_iter().map(Into::into).collect())
+            }
+            reply => reply.into_response(),
+        }
+    }
+
+    fn get_entry_by_name(name: String) -> Response<Shadow> {
+        match lookup("shadow get_entry_by_name", Request::ShadowByName(name)) {
+            Reply::Shadows(shadows) => Reply::first(shadows),
+            reply => reply.into_response(),
+        }
+    }
+}
+
+pub(crate) fn all_shadows(config: &Config) -> Response<Vec<Shadow>> {
+    let res = visibility::users(config, |v| log(libc::LOG_DEBUG, v));
+    let users = match res {
+        Ok(users) => users,
+        Err(e) => {
+            log(libc::LOG_ERR, format!("Failed to get user: {e}"));
+            return Response::TryAgain;
+        }
+    };
+
+    let validator = Validator::with_users(config, &users, log);
+    let mut shadows = vec![];
+    for user in users.iter().filter(|ur| validator.uid(ur).is_some()) {
+        match to_shadow(config, user) {
+            Ok(shadow) => shadows.push(shadow),
+            Err(e) => {
+                log(libc::LOG_ERR, format!("Failed to get credentials: {e}"));
+                return Response::TryAgain;
+            }
+        }
+    }
+
+    cache::replace_shadows(
+        &config.realm,
+        &shadows.iter().map(Into::into).collect::<Vec<_>>(),
+    );
+    Response::Success(shadows)
+}
+
+pub(crate) fn shadow_by_name(config: &Config, name: String) -> Response<Shadow> {
+    let Some(username) = config.keycloak_name(&name) else {
+        return Response::NotFound;
+    };
+    let mut query = HashMap::new();
+    query.insert("exact", "true");
+    query.insert("username", username);
+
+    let res = api::get_users(config, query, |v| log(libc::LOG_DEBUG, v));
+    let users = match res {
+        Ok(users) => users,
+        Err(e) => {
+            log(libc::LOG_ERR, format!("Failed to get user: {e}"));
+            return Response::TryAgain;
+        }
+    };
+    let [user] = users.as_slice() else {
+        return Response::NotFound;
+    };
+    let mut validator = Validator::new(config, log);
+    if let Err(e) = validator.find_duplicates_of(user) {
+        log(libc::LOG_ERR, format!("Failed to get user: {e}"));
+        return Response::TryAgain;
+    }
+    if validator.uid(user).is_none() {
+        return Response::NotFound;
+    }
+    if let Err(response) = validate::check_allowed(config, user) {
+        return response;
+    }
+    log(libc::LOG_DEBUG, format!("{user:?}"));
+
+    match to_shadow(config, user) {
+        Ok(shadow) => {
+            cache::update_shadow_cache(&config.realm, &[(&shadow).into()]);
+            Response::Success(shadow)
+        }
+        Err(e) => {
+            log(libc::LOG_ERR, format!("Failed to get credentials: {e}"));
+            Response::TryAgain
+        }
+    }
+}
+
+/// Build a shadow entry, taking the last password change from the user's
+/// password credential.
+fn to_shadow(
+    config: &Config,
+    user: &UserRepresentation,
+) -> Result<Shadow, Box<dyn std::error::Error>> {
+    let last_change = api::get_user_credentials(config, &user.id, |v| log(libc::LOG_DEBUG, v))?
+        .iter()
+        .filter(|c| c.credential_type == "password")
+        .find_map(|c| c.created_date)
+        .map(|ms| (ms / MILLIS_PER_DAY) as isize);
+
+    Ok(user.to_shadow(config, last_change))
+}
@@ -0,0 +1,17 @@
+use common::api::types::GroupRepresentation;
+use libnss::group::Group;
+
+pub trait ToGroup {
+    fn to_group(&self, gid: libc::gid_t, members: Vec<String>) -> Group;
+}
+
+impl ToGroup for GroupRepresentation {
+    fn to_group(&self, gid: libc::gid_t, members: Vec<String>) -> Group {
+        Group {
+            name: self.name.clone(),
+            passwd: "x".to_string(),
+            gid,
+            members,
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use common::{api::types::UserRepresentation, config::Config};
+use common::{api::types::UserRepresentation, config::Config, user, validate};
 use libnss::passwd::Passwd;
 
 pub trait ToPasswd {
@@ -9,17 +9,17 @@ impl ToPasswd for UserRepresentation {
     fn to_passwd(&self, config: &Config, uid: libc::uid_t) -> Passwd {
         Passwd {
             uid,
-            gecos: format!("{} {}", self.first_name, self.last_name),
-            name: self.username.clone(),
-            gid: config.group_id,
+            gecos: validate::sanitise(&user::gecos(config, self)),
+            name: config.unix_name(&self.username),
+            // Checked by the validator
+            gid: user::primary_gid(config, self).unwrap_or(config.group_id),
             passwd: "x".to_string(),
-            dir: config
-                .home_directory_parent
-                .clone()
-                .join(self.username.clone())
+            // Checked by the validator
+            dir: user::home_directory(config, self)
+                .unwrap_or_default()
                 .to_string_lossy()
                 .into_owned(),
-            shell: config.shell.clone(),
+            shell: user::shell(config, self),
         }
     }
 }
@@ -0,0 +1,67 @@
+use common::{api::types::UserRepresentation, config::Config};
+use libnss::shadow::Shadow;
+
+/// Expiry date for disabled accounts, meaning they expired on 1970-01-02.
+const EXPIRED: isize = 1;
+
+pub trait ToShadow {
+    fn to_shadow(&self, config: &Config, last_change: Option<isize>) -> Shadow;
+}
+
+impl ToShadow for UserRepresentation {
+    fn to_shadow(&self, config: &Config, last_change: Option<isize>) -> Shadow {
+        let expire_date = if self.enabled {
+            self.attributes
+                .get(&config.account_expiry_attribute_id)
+                .and_then(|v| v.first())
+                .and_then(|v| parse_days(v))
+                .unwrap_or(-1)
+        } else {
+            EXPIRED
+        };
+
+        Shadow {
+            name: config.unix_name(&self.username),
+            // Passwords are checked by Keycloak, never locally
+            passwd: "!".to_string(),
+            last_change: last_change.unwrap_or(-1),
+            change_min_days: -1,
+            change_max_days: self
+                .attributes
+                .get(&config.password_max_days_attribute_id)
+                .and_then(|v| v.first())
+                .and_then(|v| v.parse::<isize>().ok())
+                .unwrap_or(-1),
+            change_warn_days: -1,
+            change_inactive_days: -1,
+            expire_date,
+            reserved: usize::MAX,
+        }
+    }
+}
+
+/// Parse a date given either as `YYYY-MM-DD` or as days since 1970-01-01.
+fn parse_days(value: &str) -> Option<isize> {
+    let value = value.trim();
+    if let Ok(days) = value.parse::<isize>() {
+        return Some(days);
+    }
+
+    let mut parts = value.splitn(3, '-').map(str::parse::<isize>);
+    let (Some(Ok(year)), Some(Ok(month)), Some(Ok(day))) =
+        (parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(era * 146097 + day_of_era - 719468)
+}
@@ -0,0 +1,31 @@
+//! Checks on Keycloak users that give NSS responses.
+
+use common::{api::types::UserRepresentation, config::Config, visibility};
+use libnss::interop::Response;
+
+use crate::log;
+
+/// Check that a user is in an allowed group or has an allowed role, or get
+/// the response to give if not.
+pub fn check_allowed<T>(config: &Config, user: &UserRepresentation) -> Result<(), Response<T>> {
+    match visibility::is_allowed(config, user, |v| log(libc::LOG_DEBUG, v)) {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            log(
+                libc::LOG_DEBUG,
+                format!(
+                    "Hiding Keycloak user {:?}: not in an allowed group or role, or not allowed on this host",
+                    user.username
+                ),
+            );
+            Err(Response::NotFound)
+        }
+        Err(e) => {
+            log(
+                libc::LOG_ERR,
+                format!("Failed to get user's groups and roles: {e}"),
+            );
+            Err(Response::TryAgain)
+        }
+    }
+}
@@ -1,9 +1,14 @@
+use std::collections::HashMap;
+
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug)]
 pub struct UserInfoResponse {
     pub sub: String,
-    pub uid: String,
+    /// Other claims, such as the UID claim named by `uid_token_claim`, which
+    /// is absent when UIDs are derived through [`common::config::IdMapping`].
+    #[serde(flatten)]
+    claims: HashMap<String, serde_json::Value>,
 
     #[serde(rename = "email_verified")]
     _email_verified: Option<bool>,
@@ -26,3 +31,14 @@ pub struct UserInfoResponse {
     #[serde(rename = "email")]
     _email: Option<String>,
 }
+
+impl UserInfoResponse {
+    /// Get a string or number claim as a string.
+    pub fn claim(&self, name: &str) -> Option<String> {
+        match self.claims.get(name)? {
+            serde_json::Value::String(value) => Some(value.clone()),
+            serde_json::Value::Number(value) => Some(value.to_string()),
+            _ => None,
+        }
+    }
+}
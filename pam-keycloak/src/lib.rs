@@ -6,16 +6,25 @@ use std::{
     path::PathBuf,
 };
 
-use common::{config, token::TokenResponse};
+use common::{
+    api,
+    api::types::UserRepresentation,
+    config::{self, Config, UidAssignment},
+    failover,
+    token::TokenResponse,
+    uid, user,
+    validate::Validator,
+    visibility,
+};
 use copy_dir::copy_dir;
 use pamsm::{LogLvl, PamError, PamLibExt, PamMsgStyle, PamServiceModule, pam_module};
-use reqwest::blocking::Client;
 
 mod api_types;
 use api_types::UserInfoResponse;
 use walkdir::WalkDir;
 
 const DATA_UUID: &str = "keycloak-uuid";
+const DATA_REALM: &str = "keycloak-realm";
 const ENV_UID: &str = "KEYCLOAK_UID";
 const ENV_GID: &str = "KEYCLOAK_GID";
 const ENV_HOME: &str = "KEYCLOAK_HOME";
@@ -41,9 +50,8 @@ impl PamServiceModule for PamKeycloak {
     }
 
     fn acct_mgmt(pamh: pamsm::Pam, _: pamsm::PamFlags, _: Vec<String>) -> PamError {
-        match pamh.retrieve_bytes(DATA_UUID) {
-            Ok(_uuid) => PamError::SUCCESS,
-            Err(_) => PamError::USER_UNKNOWN,
+        match acct_mgmt(&pamh) {
+            Ok(r) | Err(r) => r,
         }
     }
 
@@ -120,25 +128,47 @@ fn authenticate(
     _args: Vec<String>,
 ) -> Result<PamError, PamError> {
     // Parse config
-    config::create_if_not_exists().unwrap();
-    let config = config::read().unwrap();
+    let config = load_config(&pamh)?;
 
     // Read or prompt for username
     let username = pamh.get_user(None)?.ok_or(PamError::AUTHINFO_UNAVAIL)?;
     let username = username.to_string_lossy();
 
-    // Check if user exists and return early if not.
-    let mut query = HashMap::new();
-    query.insert("exact", Cow::Borrowed("true"));
-    query.insert("username", username.clone());
-    let users = common::api::get_users(&config, query, |v| {
+    // Check if user exists in any realm and return early if not.
+    let Some((config, user)) = find_user(&pamh, &config, &username)? else {
+        return Ok(PamError::USER_UNKNOWN);
+    };
+
+    // Users outside the allowed groups and roles are treated as unknown
+    let allowed = visibility::is_allowed(&config, &user, |v| {
         let _ = pamh.syslog(LogLvl::DEBUG, &v);
     })
     .map_err(|_| PamError::AUTHINFO_UNAVAIL)?;
-    if users.len() != 1 {
+    if !allowed {
+        let _ = pamh.syslog(
+            LogLvl::NOTICE,
+            &format!("Denied user {username} as they are not in an allowed group or role, or not allowed on this host"),
+        );
         return Ok(PamError::USER_UNKNOWN);
     }
 
+    // Users that NSS would hide, such as ones named after local accounts,
+    // are treated as unknown too
+    let mut validator = Validator::new(&config, |priority, message| {
+        let _ = pamh.syslog(log_level(priority), &message);
+    });
+    if !validator.is_valid_name(&user) {
+        return Ok(PamError::USER_UNKNOWN);
+    }
+    if let Some(uid) = uid::uid_of(&config, &user) {
+        validator
+            .find_duplicates_of(&user)
+            .map_err(|_| PamError::AUTHINFO_UNAVAIL)?;
+        if !validator.is_valid(&user, uid) {
+            return Ok(PamError::USER_UNKNOWN);
+        }
+    }
+
     // Read or prompt for password
     let password = pamh.get_authtok(None)?.ok_or(PamError::AUTHINFO_UNAVAIL)?;
     let password = password.to_string_lossy();
@@ -153,35 +183,35 @@ fn authenticate(
 
     // Send direct grant request
     let mut form_data = HashMap::new();
-    form_data.insert("username", username.clone());
+    form_data.insert("username", Cow::Borrowed(user.username.as_str()));
     form_data.insert("password", password);
     form_data.insert("totp", totp);
     form_data.insert("grant_type", Cow::Borrowed("password"));
-    form_data.insert("scope", Cow::Owned(config.scopes));
-
-    let client = Client::new();
-    let res = client
-        .post(config.token_url)
-        .basic_auth(config.client_id, Some(config.client_secret))
-        .form(&form_data)
-        .send()
-        .map_err(|e| {
-            let _ = pamh.syslog(
-                LogLvl::CRIT,
-                "Failed to send request to OIDC token endpoint!",
-            );
-            let _ = pamh.syslog(LogLvl::CRIT, e.to_string().as_str());
-            PamError::AUTHINFO_UNAVAIL
-        })?
-        .json::<TokenResponse>()
-        .map_err(|e| {
-            let _ = pamh.syslog(
-                LogLvl::CRIT,
-                "Failed to parse response from OIDC token endpoint!",
-            );
-            let _ = pamh.syslog(LogLvl::CRIT, e.to_string().as_str());
-            PamError::AUTHINFO_UNAVAIL
-        })?;
+    form_data.insert("scope", Cow::Borrowed(config.scopes.as_str()));
+
+    let res = failover::send(&config, &config.token_url, |client, url| {
+        client
+            .post(url)
+            .basic_auth(&config.client_id, Some(&config.client_secret))
+            .form(&form_data)
+    })
+    .map_err(|e| {
+        let _ = pamh.syslog(
+            LogLvl::CRIT,
+            "Failed to send request to OIDC token endpoint!",
+        );
+        let _ = pamh.syslog(LogLvl::CRIT, e.to_string().as_str());
+        PamError::AUTHINFO_UNAVAIL
+    })?
+    .json::<TokenResponse>()
+    .map_err(|e| {
+        let _ = pamh.syslog(
+            LogLvl::CRIT,
+            "Failed to parse response from OIDC token endpoint!",
+        );
+        let _ = pamh.syslog(LogLvl::CRIT, e.to_string().as_str());
+        PamError::AUTHINFO_UNAVAIL
+    })?;
 
     match res {
         TokenResponse::Failure {
@@ -200,43 +230,209 @@ fn authenticate(
             return Err(PamError::USER_UNKNOWN);
         }
         TokenResponse::Success { access_token, .. } => {
-            let res = client
-                .post(config.userinfo_url)
-                .bearer_auth(access_token)
-                .send()
-                .map_err(|e| {
-                    let _ = pamh.syslog(
-                        LogLvl::CRIT,
-                        "Failed to send request to OIDC userinfo endpoint!",
-                    );
-                    let _ = pamh.syslog(LogLvl::CRIT, e.to_string().as_str());
-                    PamError::AUTH_ERR
-                })?
-                .json::<UserInfoResponse>()
-                .map_err(|e| {
-                    let _ = pamh.syslog(
-                        LogLvl::CRIT,
-                        "Failed to parse response from OIDC userinfo endpoint!",
-                    );
-                    let _ = pamh.syslog(LogLvl::CRIT, e.to_string().as_str());
-                    PamError::AUTH_ERR
-                })?;
+            let res = failover::send(&config, &config.userinfo_url, |client, url| {
+                client.post(url).bearer_auth(&access_token)
+            })
+            .map_err(|e| {
+                let _ = pamh.syslog(
+                    LogLvl::CRIT,
+                    "Failed to send request to OIDC userinfo endpoint!",
+                );
+                let _ = pamh.syslog(LogLvl::CRIT, e.to_string().as_str());
+                PamError::AUTH_ERR
+            })?
+            .json::<UserInfoResponse>()
+            .map_err(|e| {
+                let _ = pamh.syslog(
+                    LogLvl::CRIT,
+                    "Failed to parse response from OIDC userinfo endpoint!",
+                );
+                let _ = pamh.syslog(LogLvl::CRIT, e.to_string().as_str());
+                PamError::AUTH_ERR
+            })?;
             let _ = pamh.syslog(LogLvl::DEBUG, &format!("User is {res:?}"));
+            let uid = match &config.id_mapping {
+                Some(mapping) => mapping.map_id(&res.sub).to_string(),
+                None => match res.claim(&config.uid_token_claim) {
+                    Some(uid) => uid,
+                    // Users without a UID are given one on their first login
+                    None if config.uid_assignment == UidAssignment::Login
+                        && !user.attributes.contains_key(&config.uid_attribute_id) =>
+                    {
+                        uid::assign_uid(&config, &user, |priority, message| {
+                            let _ = pamh.syslog(log_level(priority), &message);
+                        })
+                        .map_err(|e| {
+                            let _ =
+                                pamh.syslog(LogLvl::CRIT, &format!("Failed to assign UID: {e}"));
+                            PamError::AUTH_ERR
+                        })?
+                        .to_string()
+                    }
+                    None => {
+                        let _ = pamh.syslog(LogLvl::CRIT, "User has no UID claim!");
+                        return Err(PamError::AUTH_ERR);
+                    }
+                },
+            };
+            if !uid
+                .parse()
+                .is_ok_and(|uid: libc::uid_t| validator.is_valid(&user, uid))
+            {
+                let _ = pamh.syslog(LogLvl::CRIT, &format!("Denied user with UID {uid}"));
+                return Err(PamError::AUTH_ERR);
+            }
+            let Some(gid) = user::primary_gid(&config, &user) else {
+                let _ = pamh.syslog(
+                    LogLvl::CRIT,
+                    "Denied user because of an invalid primary GID",
+                );
+                return Err(PamError::AUTH_ERR);
+            };
+            let Some(home) =
+                user::home_directory(&config, &user).filter(|home| user::is_safe_home(home))
+            else {
+                let _ = pamh.syslog(
+                    LogLvl::CRIT,
+                    "Denied user because of an unsafe home directory",
+                );
+                return Err(PamError::AUTH_ERR);
+            };
             let _ = pamh.send_bytes(DATA_UUID, res.sub.into_bytes(), None);
-            let _ = pamh.putenv(&format!("{ENV_UID}={}", res.uid));
-            let _ = pamh.putenv(&format!("{ENV_GID}={}", config.group_id));
-            let _ = pamh.putenv(&format!(
-                "{ENV_HOME}={}",
-                config
-                    .home_directory_parent
-                    .join(PathBuf::from(username.into_owned()))
-                    .to_str()
-                    .unwrap()
-            ));
+            let _ = pamh.send_bytes(DATA_REALM, config.realm.clone().into_bytes(), None);
+            let _ = pamh.putenv(&format!("{ENV_UID}={uid}"));
+            let _ = pamh.putenv(&format!("{ENV_GID}={gid}"));
+            let _ = pamh.putenv(&format!("{ENV_HOME}={}", home.display()));
         }
     }
 
     Ok(PamError::SUCCESS)
 }
 
+/// Read the config, creating it if missing, and find the realm's endpoints.
+fn load_config(pamh: &pamsm::Pam) -> Result<Config, PamError> {
+    config::create_if_not_exists()
+        .and_then(|()| config::load())
+        .map_err(|e| {
+            let _ = pamh.syslog(LogLvl::CRIT, &format!("Failed to load config: {e}"));
+            PamError::AUTHINFO_UNAVAIL
+        })
+}
+
+/// Find a user by their Unix name in the realm that has them.
+fn find_user(
+    pamh: &pamsm::Pam,
+    config: &Config,
+    username: &str,
+) -> Result<Option<(Config, UserRepresentation)>, PamError> {
+    for realm_config in config.realm_configs() {
+        let Some(name) = realm_config.keycloak_name(username) else {
+            continue;
+        };
+        let mut query = HashMap::new();
+        query.insert("exact", "true");
+        query.insert("username", name);
+        let users = api::get_users(&realm_config, query, |v| {
+            let _ = pamh.syslog(LogLvl::DEBUG, &v);
+        })
+        .map_err(|_| PamError::AUTHINFO_UNAVAIL)?;
+        if let Ok([user]) = <[_; 1]>::try_from(users) {
+            return Ok(Some((realm_config, user)));
+        }
+    }
+    Ok(None)
+}
+
+fn acct_mgmt(pamh: &pamsm::Pam) -> Result<PamError, PamError> {
+    let debug_log = |v: String| {
+        let _ = pamh.syslog(LogLvl::DEBUG, &v);
+    };
+    let (config, user) = match pamh.retrieve_bytes(DATA_UUID) {
+        Ok(uuid) => {
+            let config = load_config(pamh)?;
+            let realm = pamh.retrieve_bytes(DATA_REALM).unwrap_or_default();
+            let config = config
+                .realm_configs()
+                .into_iter()
+                .find(|c| c.realm.as_bytes() == realm)
+                .ok_or(PamError::USER_UNKNOWN)?;
+            let user = api::get_user(&config, &String::from_utf8_lossy(&uuid), debug_log).map_err(
+                |e| {
+                    let _ = pamh.syslog(LogLvl::CRIT, &format!("Failed to get user: {e}"));
+                    PamError::AUTHINFO_UNAVAIL
+                },
+            )?;
+            (config, user)
+        }
+        // Not authenticated by this module, such as with an SSH key
+        Err(_) => {
+            let username = pamh.get_user(None)?.ok_or(PamError::USER_UNKNOWN)?;
+            let username = username.to_string_lossy();
+            // Local accounts, such as root, are left to the other modules
+            // without asking Keycloak, which may not be reachable
+            let accounts = uid::LocalPasswd::accounts().map_err(|e| {
+                let _ = pamh.syslog(LogLvl::CRIT, &format!("Failed to read local accounts: {e}"));
+                PamError::AUTHINFO_UNAVAIL
+            })?;
+            if accounts.iter().any(|(name, _)| *name == username) {
+                return Ok(PamError::USER_UNKNOWN);
+            }
+            let config = load_config(pamh)?;
+            find_user(pamh, &config, &username)?.ok_or(PamError::USER_UNKNOWN)?
+        }
+    };
+
+    if !user.enabled {
+        let _ = pamh.syslog(
+            LogLvl::NOTICE,
+            &format!("Denied user {} as they are disabled", user.username),
+        );
+        return Ok(PamError::ACCT_EXPIRED);
+    }
+    if !user.required_actions.is_empty() {
+        let _ = pamh.syslog(
+            LogLvl::NOTICE,
+            &format!(
+                "Denied user {} as they have required actions: {:?}",
+                user.username, user.required_actions
+            ),
+        );
+        // These can only be done in Keycloak, which also refuses the direct
+        // grant until then
+        return Ok(PamError::ACCT_EXPIRED);
+    }
+
+    // Checked again for logins that didn't authenticate through this module
+    let allowed = visibility::may_log_in(&config, &user, debug_log).map_err(|e| {
+        let _ = pamh.syslog(LogLvl::CRIT, &format!("Failed to check access: {e}"));
+        PamError::AUTHINFO_UNAVAIL
+    })?;
+    if !allowed {
+        let _ = pamh.syslog(
+            LogLvl::NOTICE,
+            &format!(
+                "Denied user {} as they are not in an allowed group or role, or not allowed on this host",
+                user.username
+            ),
+        );
+        return Ok(PamError::PERM_DENIED);
+    }
+
+    Ok(PamError::SUCCESS)
+}
+
+/// Convert a syslog priority to a PAM log level.
+fn log_level(priority: libc::c_int) -> LogLvl {
+    match priority {
+        libc::LOG_EMERG => LogLvl::EMERG,
+        libc::LOG_ALERT => LogLvl::ALERT,
+        libc::LOG_CRIT => LogLvl::CRIT,
+        libc::LOG_ERR => LogLvl::ERR,
+        libc::LOG_WARNING => LogLvl::WARNING,
+        libc::LOG_NOTICE => LogLvl::NOTICE,
+        libc::LOG_INFO => LogLvl::INFO,
+        _ => LogLvl::DEBUG,
+    }
+}
+
 pam_module!(PamKeycloak);